@@ -33,6 +33,7 @@ fi
 cp -rvf "$ROOT_PATH/rbnserver/migrations" "$TARGET_DIR"
 cp -rvf "$ROOT_PATH/rbnserver/rsfdata" "$TARGET_DIR"
 cp -rvf "$ROOT_PATH/rbnserver/templates" "$TARGET_DIR"
+cp -rvf "$ROOT_PATH/rbnserver/RBNServer.toml" "$TARGET_DIR"
 
 
 if [ "$2" == "release" ]; then
@@ -48,6 +49,7 @@ if [ "$2" == "release" ]; then
 	cp -rvf "$ROOT_PATH/rbnserver/migrations" "$RELEASE_TEMP"
 	cp -rvf "$ROOT_PATH/rbnserver/rsfdata" "$RELEASE_TEMP"
 	cp -rvf "$ROOT_PATH/rbnserver/templates" "$RELEASE_TEMP"
+	cp -rvf "$ROOT_PATH/rbnserver/RBNServer.toml" "$RELEASE_TEMP"
 	cp -rvf "$TARGET_DIR/rbnserver" "$RELEASE_TEMP"
 
 	cd "$RELEASE_DIR"
@@ -1,4 +1,5 @@
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
 use tokio::runtime::Builder;
 use tokio::sync::mpsc::{Sender, Receiver};
 use tokio::task::JoinHandle;
@@ -6,11 +7,12 @@ use tokio::time::Instant;
 use std::sync::Arc;
 use std::time::Duration;
 use rbnproto::httpapi::{RaceInfo, RaceState};
-use rbnproto::metaapi::{DataFormat, MetaHeader, MetaRaceProgress, MetaRaceResult, MetaRaceRidicule, MetaRaceState, RaceAccess, RaceCmd, RaceUpdate, META_HEADER_LEN};
+use rbnproto::API_VERSION_STRING;
+use rbnproto::metaapi::{decode_body, decode_race_cmd, decode_race_progress, decode_race_result, encode_frame, unix_millis, ClockSync, DataFormat, MetaCodecError, MetaDecoder, MetaHandshake, MetaHeader, MetaKeepAlive, MetaPing, MetaPong, MetaRaceResult, MetaRaceResultPage, MetaRaceRidicule, MetaRaceState, MetaRevision, RaceAccess, RaceCmd, RaceUpdate};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, OnceCell};
+use tokio::sync::{watch, Mutex, OnceCell};
 
 use crate::components::player::AudioPlayer;
 use crate::components::store::RacingStore;
@@ -21,6 +23,7 @@ use crate::rbnhelper::InnerMsg;
 pub enum TaskMsg {
     MsgStartStage(String),
     MsgStopStage,
+    MsgUpdateToken(String),
 }
 
 #[derive(Default, Clone)]
@@ -39,7 +42,8 @@ impl RBNBackend {
     pub fn run(&mut self, tx: Sender<TaskMsg>, mut rx: Receiver<TaskMsg>, notifier: &Sender<InnerMsg>) {
         self.tx = Some(tx.clone());
         let server = self.meta_addr.clone();
-        let token = self.user_token.clone();
+        // stages read the latest token on every reconnect, a refresh may replace it.
+        let (token_tx, token) = watch::channel(self.user_token.clone());
         let notifier = notifier.clone();
         std::thread::spawn(move || {
             Builder::new_multi_thread().enable_all().build().unwrap().block_on(async move {
@@ -56,6 +60,9 @@ impl RBNBackend {
                                     stage_task = None;
                                 }
                             }
+                            TaskMsg::MsgUpdateToken(newtoken) => {
+                                token_tx.send_replace(newtoken);
+                            }
                         }
                     }
                 }
@@ -70,60 +77,205 @@ impl RBNBackend {
     }
 }
 
-fn spawn_one_stage(server: &String, token: &String, race: &String, notifier: &Sender<InnerMsg>) -> JoinHandle<()> {
+/// Shared by the game tasks of a stage, swapped when the meta connection is resumed.
+type MetaWriter = Arc<Mutex<Option<OwnedWriteHalf>>>;
+
+/// Kept across reconnects of a stage: the protocol revision of the server, the
+/// clock offset to it and the result pages of the last race, the overlay shows
+/// one page at a time.
+#[derive(Default)]
+struct StageSync {
+    revision: Option<MetaRevision>,
+    clock: ClockSync,
+    results: Vec<Vec<MetaRaceResult>>,
+    shown: usize,
+}
+
+type MetaStage = Arc<Mutex<StageSync>>;
+
+impl StageSync {
+    fn get_revision(&self) -> MetaRevision {
+        self.revision.unwrap_or_default()
+    }
+
+    fn feed_result_page(&mut self, page: MetaRaceResultPage) {
+        if page.page == 0 {
+            self.results = vec![vec![]; page.pages as usize];
+            self.shown = 0;
+        }
+
+        let index = page.page as usize;
+        if let Some(results) = self.results.get_mut(index) {
+            *results = page.results;
+            if index == self.shown {
+                RBRGame::default().feed_race_result(results);
+            }
+        }
+    }
+
+    fn show_next_result_page(&mut self) {
+        if self.results.len() > 1 {
+            self.shown = (self.shown + 1) % self.results.len();
+            RBRGame::default().feed_race_result(&self.results[self.shown]);
+        }
+    }
+}
+
+static RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+static RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(16);
+static PING_BURST: u32 = 4;
+static PING_BURST_INTERVAL: Duration = Duration::from_millis(200);
+static PING_INTERVAL: Duration = Duration::from_secs(2);
+/// The server sends keepalive frames every second, a silent connection is dead.
+static META_SILENT_TIMEOUT: Duration = Duration::from_secs(10);
+static RESULT_PAGE_INTERVAL: Duration = Duration::from_secs(5);
+
+fn spawn_one_stage(server: &String, token: &watch::Receiver<String>, race: &String, notifier: &Sender<InnerMsg>) -> JoinHandle<()> {
     let meta_addr = server.clone();
-    let user_token = token.clone();
+    let token = token.clone();
     let room_name = race.clone();
     let notifier = notifier.clone();
 
     tokio::spawn(async move {
-        let stream = TcpStream::connect(meta_addr).await.unwrap();
-        let (mut reader, mut writer) = stream.into_split();
-
-        let access = RaceAccess {token: user_token.clone(), room: room_name.clone()};
-        let body = bincode::serialize(&access).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUserAccess}).unwrap();
-        writer.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
-
-        let mut recvbuf = vec![0u8; 1024];
-        let mut remain = Vec::<u8>::new();
-        let writer_clone = Arc::new(Mutex::new(writer));
-        while let Ok(n) = reader.read(&mut recvbuf).await {
-            if n == 0 {
-                break;
+        let writer: MetaWriter = Arc::new(Mutex::new(None));
+        let stage: MetaStage = Arc::new(Mutex::new(StageSync::default()));
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        loop {
+            match TcpStream::connect(&meta_addr).await {
+                Ok(stream) => {
+                    let (reader, half) = stream.into_split();
+                    writer.lock().await.replace(half);
+                    stage.lock().await.revision = None;
+
+                    let user_token = token.borrow().clone();
+                    let handshake = MetaHandshake {version: API_VERSION_STRING.to_string(), accepted: true, notice: String::new()};
+                    send_frame(&writer, DataFormat::FmtHandshake, &handshake).await;
+                    let access = RaceAccess {token: user_token.clone(), room: room_name.clone()};
+                    send_frame(&writer, DataFormat::FmtUserAccess, &access).await;
+
+                    let pinger = tokio::spawn(ping_server(writer.clone(), stage.clone()));
+                    let resume = serve_one_stage(reader, &user_token, &room_name, &writer, &stage, &notifier, &mut backoff).await;
+                    pinger.abort();
+                    writer.lock().await.take();
+                    if !resume {
+                        return;
+                    }
+                    warn!("meta connection lost, reconnect in {:?}", backoff);
+                }
+                Err(e) => warn!("failed to connect meta server: {}, retry in {:?}", e, backoff),
             }
 
-            // 处理接收的数据
-            // 这里只是简单地将接收到的数据打印出来
-            // trace!("Received data: {:?}", &recvbuf[..n]);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+        }
+    })
+}
 
-            let buffer = [&remain[..], &recvbuf[..n]].concat();
-            let datalen = buffer.len();
-            let mut offset = 0 as usize;
+/// Returns whether the connection should be resumed after it closed.
+async fn serve_one_stage(mut reader: OwnedReadHalf, token: &String, room: &String, writer: &MetaWriter, stage: &MetaStage, notifier: &Sender<InnerMsg>, backoff: &mut Duration) -> bool {
+    let mut recvbuf = vec![0u8; 1024];
+    let mut decoder = MetaDecoder::default();
+    let mut pages = tokio::time::interval(RESULT_PAGE_INTERVAL);
+    let mut last_read = Instant::now();
+    loop {
+        // servers before keepalive frames may stay silent while waiting for the race.
+        let echoes = stage.lock().await.revision.is_none_or(|x| x >= MetaRevision::KeepAlive);
+        let n = tokio::select! {
+            read = reader.read(&mut recvbuf) => match read {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            },
+            _ = tokio::time::sleep_until(last_read + META_SILENT_TIMEOUT), if echoes => {
+                warn!("meta connection silent for {:?}", META_SILENT_TIMEOUT);
+                break;
+            }
+            _ = pages.tick() => {
+                stage.lock().await.show_next_result_page();
+                continue;
+            }
+        };
 
-            while offset + META_HEADER_LEN <= datalen {
-                if datalen < META_HEADER_LEN {
-                    break;
+        last_read = Instant::now();
+        *backoff = RECONNECT_BACKOFF_MIN;
+        decoder.feed(&recvbuf[..n]);
+        loop {
+            match decoder.next_frame() {
+                Ok(Some((head, pack_data))) => {
+                    match meta_message_handle(head, &pack_data, token, room, writer, stage, notifier).await {
+                        Ok(true) => {}
+                        Ok(false) => return false,
+                        Err(e) => {
+                            warn!("drop meta connection on bad packet: {}", e);
+                            return false;
+                        }
+                    }
                 }
-                let head: MetaHeader = bincode::deserialize(&buffer[offset..offset+META_HEADER_LEN]).unwrap();
-
-                if (offset + META_HEADER_LEN + head.length as usize) > datalen {
-                    break;
-                }     
-                let pack_data = &buffer[offset+META_HEADER_LEN..offset+META_HEADER_LEN+head.length as usize];
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("drop meta connection on bad frame: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
 
-                meta_message_handle(head.clone(), pack_data, &user_token, &room_name, &writer_clone, &notifier).await;
-                offset += META_HEADER_LEN + head.length as usize;
+async fn send_frame<T: Serialize>(writer: &MetaWriter, format: DataFormat, body: &T) {
+    match encode_frame(format, body) {
+        Ok(frame) => {
+            if let Some(writer) = writer.lock().await.as_mut() {
+                writer.write_all(&frame).await.unwrap_or(());
             }
-            remain = (&buffer[offset..]).to_vec();
         }
-    })
+        Err(e) => warn!("failed to encode meta frame: {}", e),
+    }
+}
+
+/// Keep the clock offset to the server fresh, a short burst first for the initial estimate.
+async fn ping_server(writer: MetaWriter, stage: MetaStage) {
+    loop {
+        match stage.lock().await.revision {
+            Some(revision) if revision >= MetaRevision::TimedStart => break,
+            Some(_) => return,
+            None => {}
+        }
+        tokio::time::sleep(PING_BURST_INTERVAL).await;
+    }
+
+    let mut round = 0u32;
+    loop {
+        send_frame(&writer, DataFormat::FmtPing, &MetaPing {client_time: unix_millis()}).await;
+        round += 1;
+        tokio::time::sleep(if round < PING_BURST { PING_BURST_INTERVAL } else { PING_INTERVAL }).await;
+    }
 }
 
-async fn meta_message_handle(head: MetaHeader, pack_data: &[u8], token: &String, room: &String, writer: &Arc<Mutex<OwnedWriteHalf>>, notifier: &Sender<InnerMsg>) {
+/// Returns false once the server rejected the connection.
+async fn meta_message_handle(head: MetaHeader, pack_data: &[u8], token: &String, room: &String, writer: &MetaWriter, stage: &MetaStage, notifier: &Sender<InnerMsg>) -> Result<bool, MetaCodecError> {
     match head.format {
+        DataFormat::FmtHandshake => {
+            let handshake: MetaHandshake = decode_body(pack_data)?;
+            if !handshake.accepted {
+                info!("meta handshake rejected by server [{}]", handshake.version);
+                notifier.send(InnerMsg::MsgUpdateNotice(handshake.notice)).await.unwrap();
+                return Ok(false);
+            }
+            stage.lock().await.revision = Some(MetaRevision::of(&handshake.version));
+        }
+
+        DataFormat::FmtKeepAlive => { // echoed right away, the server measures our latency.
+            let keepalive: MetaKeepAlive = decode_body(pack_data)?;
+            send_frame(writer, DataFormat::FmtKeepAlive, &keepalive).await;
+        }
+
+        DataFormat::FmtPong => {
+            let pong: MetaPong = decode_body(pack_data)?;
+            stage.lock().await.clock.feed(&pong, unix_millis());
+        }
+
         DataFormat::FmtRaceCommand => {
-            let cmd: RaceCmd = bincode::deserialize(pack_data).unwrap();
+            let cmd = decode_race_cmd(pack_data, stage.lock().await.get_revision())?;
             match cmd {
                 RaceCmd::RaceCmdPrepare(info) => {
                     info!("recv cmd to prepare game: {:?}", info);
@@ -133,9 +285,10 @@ async fn meta_message_handle(head: MetaHeader, pack_data: &[u8], token: &String,
                     info!("recv cmd to load game");
                     tokio::spawn(start_game_load(token.clone(), room.clone(), writer.clone()));
                 }
-                RaceCmd::RaceCmdStart => {
-                    info!("recv cmd to start game");
-                    tokio::spawn(start_game_race(token.clone(), room.clone(), writer.clone()));
+                RaceCmd::RaceCmdStart(start) => {
+                    let clock = &stage.lock().await.clock;
+                    info!("recv cmd to start game at {}, clock offset {:?}ms rtt {:?}ms", start, clock.get_offset(), clock.get_rtt());
+                    tokio::spawn(start_game_race(token.clone(), room.clone(), writer.clone(), clock.to_local(start)));
                 }
                 RaceCmd::RaceCmdUpload => {
                     info!("recv cmd to upload race data");
@@ -150,35 +303,37 @@ async fn meta_message_handle(head: MetaHeader, pack_data: &[u8], token: &String,
         }
 
         DataFormat::FmtSyncRaceState => {
-            let state: Vec<MetaRaceState> = bincode::deserialize(pack_data).unwrap();
+            let state: Vec<MetaRaceState> = decode_body(pack_data)?;
             RBRGame::default().feed_race_state(&state);
         }
 
         DataFormat::FmtSyncRaceData => {
-            let progress: Vec<MetaRaceProgress> = bincode::deserialize(pack_data).unwrap();
+            let progress = decode_race_progress(pack_data, stage.lock().await.get_revision())?;
             RBRGame::default().feed_race_data(&progress);
         }
 
         DataFormat::FmtSyncRaceRidicule => {
-            let ridicule: MetaRaceRidicule = bincode::deserialize(pack_data).unwrap();
+            let ridicule: MetaRaceRidicule = decode_body(pack_data)?;
             info!("recv cmd to ridicule: {:?}", ridicule);
             tokio::spawn(start_game_ridicule(ridicule));
         }
 
         DataFormat::FmtSyncRaceResult => {
-            let result: Vec<MetaRaceResult> = bincode::deserialize(pack_data).unwrap();
-            RBRGame::default().feed_race_result(&result);
+            let mut stage = stage.lock().await;
+            let page = decode_race_result(pack_data, stage.get_revision())?;
+            stage.feed_result_page(page);
         }
 
         DataFormat::FmtSyncRaceNotice => {
-            let notice: String = bincode::deserialize(pack_data).unwrap();
+            let notice: String = decode_body(pack_data)?;
             notifier.send(InnerMsg::MsgUpdateNotice(notice)).await.unwrap();
         }
         _ => {}
     }
+    Ok(true)
 }
 
-async fn start_game_prepare(token: String, room: String, writer: Arc<Mutex<OwnedWriteHalf>>, info: RaceInfo, notifier: Sender<InnerMsg>) {
+async fn start_game_prepare(token: String, room: String, writer: MetaWriter, info: RaceInfo, notifier: Sender<InnerMsg>) {
     let mut rbr = RBRGame::default();
     let user_token = token.clone();
     let room_name = room.clone();
@@ -205,14 +360,12 @@ async fn start_game_prepare(token: String, room: String, writer: Arc<Mutex<Owned
         }
 
         let update = RaceUpdate {token: user_token.clone(), room: room_name.clone(), state: RaceState::RaceReady};
-        let body = bincode::serialize(&update).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUpdateState}).unwrap();
-        writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+        send_frame(&writer, DataFormat::FmtUpdateState, &update).await;
     });
 }
 
 // need to start this task when stage loaded.
-async fn start_game_load(token: String, room: String, writer: Arc<Mutex<OwnedWriteHalf>>) {
+async fn start_game_load(token: String, room: String, writer: MetaWriter) {
     let mut rbr = RBRGame::default();
     let user_token = token.clone();
     let room_name = room.clone();
@@ -225,9 +378,7 @@ async fn start_game_load(token: String, room: String, writer: Arc<Mutex<OwnedWri
                 RaceState::RaceLoaded | RaceState::RaceRunning => {
                     tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
                     let update = RaceUpdate {token: user_token.clone(), room: room_name.clone(), state: RaceState::RaceLoaded};
-                    let body = bincode::serialize(&update).unwrap();
-                    let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUpdateState}).unwrap();
-                    writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+                    send_frame(&writer, DataFormat::FmtUpdateState, &update).await;
                     break;
                 },
                 _ => {},
@@ -237,21 +388,20 @@ async fn start_game_load(token: String, room: String, writer: Arc<Mutex<OwnedWri
     });
 }
 
-async fn start_game_race(token: String, room: String, writer: Arc<Mutex<OwnedWriteHalf>>) {
+/// Start at the local instant matching the server start, late commands start right away.
+async fn start_game_race(token: String, room: String, writer: MetaWriter, start: i64) {
     let user_token = token.clone();
     let room_name = room.clone();
     AudioPlayer::notification("begin_race.wav").play();
-    tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+    tokio::time::sleep(Duration::from_millis((start - unix_millis()).max(0) as u64)).await;
     RBRGame::default().start();
     tokio::spawn(async move {
         let update = RaceUpdate {token: user_token.clone(), room: room_name, state: RaceState::RaceStarted};
-        let body = bincode::serialize(&update).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUpdateState}).unwrap();
-        writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+        send_frame(&writer, DataFormat::FmtUpdateState, &update).await;
     });
 }
 
-async fn start_game_upload(token: String, room: String, writer: Arc<Mutex<OwnedWriteHalf>>) {
+async fn start_game_upload(token: String, room: String, writer: MetaWriter) {
     let mut rbr = RBRGame::default();
     let user_token = token.clone();
     let room_name = room.clone();
@@ -263,18 +413,14 @@ async fn start_game_upload(token: String, room: String, writer: Arc<Mutex<OwnedW
                 RaceState::RaceExitMenu => {
                     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await; // give some time to execute exit to menu state.
                     let update = RaceUpdate {token: user_token.clone(), room: room_name.clone(), state: state.clone()};
-                    let body = bincode::serialize(&update).unwrap();
-                    let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUpdateState}).unwrap();
-                    writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+                    send_frame(&writer, DataFormat::FmtUpdateState, &update).await;
                     break;
                 },
                 RaceState::RaceRetired | RaceState::RaceFinished => {
                     once_finished.get_or_init(|| {
                         async {
                             let update = RaceUpdate {token: user_token.clone(), room: room_name.clone(), state: state.clone()};
-                            let body = bincode::serialize(&update).unwrap();
-                            let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUpdateState}).unwrap();
-                            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+                            send_frame(&writer, DataFormat::FmtUpdateState, &update).await;
                         }
                     }).await;
                 },
@@ -287,9 +433,7 @@ async fn start_game_upload(token: String, room: String, writer: Arc<Mutex<OwnedW
                     data.token = user_token.clone();
                     data.room = room_name.clone();
                     data.horn = horn;
-                    let body = bincode::serialize(&data).unwrap();
-                    let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtUploadData}).unwrap();
-                    writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+                    send_frame(&writer, DataFormat::FmtUploadData, &data).await;
                 },
                 _ => {},
             }
@@ -12,7 +12,10 @@ pub struct RacingStore {
     pub autojoin: bool,
     pub user_name: String,
     pub user_passwd: String,
+    /// One-time code of an admin reset, claims the existing account with the passwd.
+    pub user_claim: String,
     pub user_token: String,
+    pub user_refresh: String,
     pub brief_news: String,
     pub noticeinfo: String,
     pub scoreinfo: UserScore,
@@ -31,7 +34,6 @@ impl RacingStore {
 
     pub fn load_config(&mut self) {
         self.user_name = RBRGame::default().get_user().to_string();
-        self.user_passwd = String::from("simrallycn");
         info!("Parsed game user [{}] success", self.user_name);
 
         self.scoreinfo.license = "Rookie".to_string();
@@ -44,6 +46,8 @@ impl RacingStore {
                 self.server_addr = conf.get_from_or(Some("Server"), "Host", "127.0.0.1").parse().unwrap();
                 self.server_port = conf.get_from_or(Some("Server"), "HttpPort", "23555").parse().unwrap();
                 self.meta_port = conf.get_from_or(Some("Server"), "DataPort", "23556").parse().unwrap();
+                self.user_passwd = conf.get_from_or(Some("Account"), "Passwd", "").to_string();
+                self.user_claim = conf.get_from_or(Some("Account"), "Claim", "").to_string();
             }
         }
 
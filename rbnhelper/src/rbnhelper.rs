@@ -1,6 +1,6 @@
 use std::vec;
 use log::info;
-use rbnproto::httpapi::{RaceInfo, RaceQuery, UserHeart, UserLogin, UserQuery, UserScore};
+use rbnproto::httpapi::{RaceInfo, RaceQuery, UserHeart, UserLogin, UserRefresh, UserRegister, UserScore, UserToken, VersionQuery, PASSWD_MIN_LEN};
 use rbnproto::metaapi::{RaceJoin, RaceLeave};
 use rbnproto::API_VERSION_STRING;
 use reqwest::StatusCode;
@@ -19,7 +19,8 @@ use crate::overlay::Overlay;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 pub enum InnerMsg {
-    MsgUserLogined(String),
+    MsgUserLogined(UserToken),
+    MsgUserRefreshed(UserToken),
     MsgUpdateNews(String),
     MsgUpdateScore(UserScore),
     MsgUpdateNotice(String),
@@ -93,21 +94,54 @@ impl RBNHelper {
     fn check_and_login(&mut self) {
         let url_ver = self.store.get_http_url("api/version");
         let url_login = self.store.get_http_url("api/user/login");
+        let url_register = self.store.get_http_url("api/user/register");
         let user = UserLogin{name: self.store.user_name.clone(), passwd: self.store.user_passwd.clone()};
+        let claim = Some(self.store.user_claim.clone()).filter(|x| !x.is_empty());
+        let query = VersionQuery{version: API_VERSION_STRING.to_string()};
         let tx = self.tx.clone();
+        if user.passwd.len() < PASSWD_MIN_LEN {
+            let notice = format!("Set [Account] Passwd in RBNHelper.ini, at least {} characters.", PASSWD_MIN_LEN);
+            let _ = tx.try_send(InnerMsg::MsgUpdateNews(notice));
+            return;
+        }
+
         tokio::runtime::Runtime::new().unwrap().block_on(async move {
-            let res = reqwest::get(url_ver).await;
+            let res = reqwest::Client::new().post(url_ver).json(&query).send().await;
             if let Ok(res) = res {
-                if res.status() == StatusCode::OK {
-                    let version = res.text().await.unwrap();
-                    if version == API_VERSION_STRING {
-                        let res = reqwest::Client::new().post(url_login).json(&user).send().await.unwrap();
+                match res.status() {
+                    StatusCode::OK => {
+                        let version = res.text().await.unwrap();
+                        info!("Server version [{}] is compatible.", version);
+                        let mut res = reqwest::Client::new().post(&url_login).json(&user).send().await.unwrap();
+                        // first time online registers the account, an admin reset code claims an existing one.
+                        let register = match res.status() {
+                            StatusCode::NOT_FOUND => Some(UserRegister{name: user.name.clone(), passwd: user.passwd.clone(), claim: None}),
+                            StatusCode::UNAUTHORIZED if claim.is_some() => Some(UserRegister{name: user.name.clone(), passwd: user.passwd.clone(), claim: claim.clone()}),
+                            _ => None,
+                        };
+                        if let Some(register) = register {
+                            let reg = reqwest::Client::new().post(url_register).json(&register).send().await.unwrap();
+                            if reg.status() == StatusCode::OK {
+                                res = reqwest::Client::new().post(&url_login).json(&user).send().await.unwrap();
+                            }
+                        }
+
                         if res.status() == StatusCode::OK {
-                            let token = res.text().await.unwrap();
+                            let text = res.text().await.unwrap();
+                            let token: UserToken = serde_json::from_str(&text).unwrap();
                             tx.send(InnerMsg::MsgUserLogined(token)).await.unwrap();
+                        } else if claim.is_some() {
+                            tx.send(InnerMsg::MsgUpdateNews("Login failed, check [Account] Passwd or ask an admin for a new [Account] Claim code.".to_string())).await.unwrap();
+                        } else {
+                            tx.send(InnerMsg::MsgUpdateNews("Login failed, check [Account] Passwd in RBNHelper.ini.".to_string())).await.unwrap();
                         }
-                    } else {
-                        tx.send(InnerMsg::MsgUpdateNews("Version is out of date, need to update.".to_string())).await.unwrap();
+                    }
+                    StatusCode::UPGRADE_REQUIRED => {
+                        let notice = res.text().await.unwrap();
+                        tx.send(InnerMsg::MsgUpdateNews(notice)).await.unwrap();
+                    }
+                    _ => {
+                        tx.send(InnerMsg::MsgUpdateNews("Server version is not compatible, need to update.".to_string())).await.unwrap();
                     }
                 }
             }
@@ -126,12 +160,21 @@ impl RBNHelper {
             match msg {
                 InnerMsg::MsgUserLogined(token) => {
                     info!("User Logined RBN Server [{}] success.", self.store.get_http_uri());
-                    self.store.user_token = token;
+                    self.store.user_token = token.token;
+                    self.store.user_refresh = token.refresh;
                     let (tx, rx) = channel::<TaskMsg>(16);
                     self.backend.init(&self.store);
                     self.backend.run(tx, rx, &self.tx);
                     self.keep_alive();
                 }
+                InnerMsg::MsgUserRefreshed(token) => {
+                    info!("User session refreshed, expire at [{}].", token.expire);
+                    if self.store.user_token != token.token {
+                        self.backend.trigger(TaskMsg::MsgUpdateToken(token.token.clone()));
+                    }
+                    self.store.user_token = token.token;
+                    self.store.user_refresh = token.refresh;
+                }
                 InnerMsg::MsgUpdateNews(news) => {
                     self.store.brief_news = news;
                 }
@@ -188,12 +231,13 @@ impl RBNHelper {
     // need to call by hooking hotlap and practice menu in.
     pub fn join_race(&mut self, race: &String) -> bool {
         if self.is_logined() && self.is_autojoin() {
-            let race_join = RaceJoin {token: self.store.user_token.clone(), room: race.clone(), passwd: None};
+            let token = self.store.user_token.clone();
+            let race_join = RaceJoin {token: token.clone(), room: race.clone(), passwd: None};
             let join_url = self.store.get_http_url("api/race/join");
             let info_url = self.store.get_http_url("api/race/info");
             let info_query = RaceQuery {name: race.clone()};
             return tokio::runtime::Runtime::new().unwrap().block_on(async move {
-                let res = reqwest::Client::new().post(join_url).json(&race_join).send().await;
+                let res = reqwest::Client::new().post(join_url).bearer_auth(&token).json(&race_join).send().await;
                 if let Ok(res) = res {
                     match res.status() {
                         StatusCode::OK => {
@@ -223,10 +267,11 @@ impl RBNHelper {
     // need to call by hooking exit hotlap and practice menu.
     pub fn leave_race(&mut self, race: &String) -> bool {
         if self.is_logined() && self.is_autojoin() {
-            let user: RaceLeave = RaceLeave{ token: self.store.user_token.clone(), room: race.clone() };
+            let token = self.store.user_token.clone();
+            let user: RaceLeave = RaceLeave{ token: token.clone(), room: race.clone() };
             let url = self.store.get_http_url("api/race/leave");
             tokio::runtime::Runtime::new().unwrap().block_on(async move {
-                let res = reqwest::Client::new().post(url).json(&user).send().await;
+                let res = reqwest::Client::new().post(url).bearer_auth(&token).json(&user).send().await;
                 if let Ok(res) = res {
                     if res.status() == StatusCode::OK {
                         AudioPlayer::notification("exit.wav").play();
@@ -258,10 +303,10 @@ impl RBNHelper {
     pub fn fetch_user_score(&mut self) {
         if self.is_logined() {
             let url = self.store.get_http_url("api/user/score");
-            let query = UserQuery { token: self.store.user_token.clone() };
+            let token = self.store.user_token.clone();
             let tx = self.tx.clone();
             tokio::runtime::Runtime::new().unwrap().block_on(async move {
-                let res = reqwest::Client::new().get(&url).json(&query).send().await;
+                let res = reqwest::Client::new().get(&url).bearer_auth(&token).send().await;
                 if let Ok(res) = res {
                     if res.status() == StatusCode::OK {
                         let text = res.text().await.unwrap();
@@ -276,15 +321,32 @@ impl RBNHelper {
     pub fn keep_alive(&mut self) {
         if self.is_logined() {
             let url = self.store.get_http_url("api/user/heartbeat");
-            let user = UserHeart { token: self.store.user_token.clone() };
+            let url_refresh = self.store.get_http_url("api/user/refresh");
+            let mut user = UserHeart { token: self.store.user_token.clone() };
+            let mut refresh = UserRefresh { refresh: self.store.user_refresh.clone() };
+            let tx = self.tx.clone();
             std::thread::spawn(move || {
                 tokio::runtime::Runtime::new().unwrap().block_on(async move {
                     loop {
-                        let _res = reqwest::Client::new().post(&url).json(&user).send().await;
+                        let res = reqwest::Client::new().post(&url).bearer_auth(&user.token).json(&user).send().await;
+                        if let Ok(res) = res {
+                            if res.status() == StatusCode::UNAUTHORIZED { // session expired or dropped, refresh to keep it.
+                                let res = reqwest::Client::new().post(&url_refresh).json(&refresh).send().await;
+                                if let Ok(res) = res {
+                                    if res.status() == StatusCode::OK {
+                                        let text = res.text().await.unwrap();
+                                        let token: UserToken = serde_json::from_str(&text).unwrap();
+                                        user.token = token.token.clone();
+                                        refresh.refresh = token.refresh.clone();
+                                        tx.send(InnerMsg::MsgUserRefreshed(token)).await.unwrap();
+                                    }
+                                }
+                            }
+                        }
                         tokio::time::sleep_until(Instant::now() + tokio::time::Duration::from_secs(10)).await;
                     }
                 });
             });
         }
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::metaapi::{MetaRaceProgress, MetaRaceResult};
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum RaceState {
@@ -16,7 +17,7 @@ pub enum RaceState {
     RaceExitMenu,
 }
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum RoomState {
     #[default]
     RoomFree,
@@ -24,17 +25,61 @@ pub enum RoomState {
     RoomRaceOn,
 }
 
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RaceScoring {
+    NoScore,
+    #[default]
+    WrcPoints,
+    TimeBased,
+    Custom(Vec<i32>),
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct VersionQuery {
+    pub version: String,
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct UserLogin {
     pub name: String,
     pub passwd: String,
 }
 
+pub static PASSWD_MIN_LEN: usize = 6;
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct UserRegister {
+    pub name: String,
+    pub passwd: String,
+    /// One-time code from an admin reset, required to claim an existing account.
+    #[serde(default)]
+    pub claim: Option<String>,
+}
+
+/// Handed to the player by an admin, registering with it sets a new password.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct UserClaim {
+    pub name: String,
+    pub claim: String,
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct UserLogout {
     pub token: String,
 }
 pub type UserQuery = UserLogout;
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct UserToken {
+    pub token: String,
+    pub refresh: String,
+    pub expire: i64,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct UserRefresh {
+    pub refresh: String,
+}
 pub type UserHeart = UserLogout;
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -47,6 +92,29 @@ pub struct UserScore {
     pub name: String,
     pub license: String,
     pub score: i32,
+    #[serde(default)]
+    pub rating: f64,
+    #[serde(default)]
+    pub deviation: f64,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RatingRecord {
+    pub race_id: i64,
+    pub rating: f64,
+    pub deviation: f64,
+    pub delta: f64,
+    pub created_at: String,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct UserRating {
+    pub name: String,
+    pub license: String,
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+    pub history: Vec<RatingRecord>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -85,10 +153,22 @@ pub struct RaceConfig {
     pub setup_id: u32,
 }
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct RaceUserState {
     pub name: String,
     pub state: RaceState,
+    /// Meta connection round trip time in milliseconds, none while disconnected.
+    pub latency: Option<u32>,
+}
+
+/// A room on the live dashboard page.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RaceOverview {
+    pub brief: RaceBrief,
+    pub info: RaceInfo,
+    pub players: Vec<RaceUserState>,
+    /// Unix timestamp of the next scheduled start.
+    pub next_start: Option<i64>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -97,6 +177,11 @@ pub struct RaceCreate {
     pub info: RaceInfo,
     pub locked: bool,
     pub passwd: Option<String>,
+    #[serde(default)]
+    pub scoring: RaceScoring,
+    /// Most players in the room, up to the room limit of the server when unset.
+    #[serde(default)]
+    pub limit: Option<u32>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -109,4 +194,94 @@ pub struct RaceInfoUpdate {
 pub struct RaceConfigUpdate {
     pub token: String,
     pub cfg: RaceConfig,
-}
\ No newline at end of file
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RaceHistoryQuery {
+    pub page: u32,
+    pub size: u32,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RaceRecord {
+    pub race_id: i64,
+    pub room: String,
+    pub stage: String,
+    pub car: String,
+    pub skytype: String,
+    pub players: u32,
+    pub position: u32,
+    pub splittime1: f32,
+    pub splittime2: f32,
+    pub finishtime: f32,
+    pub difftime: f32,
+    pub score: i32,
+    pub finished_at: String,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RaceHistory {
+    pub total: u32,
+    pub page: u32,
+    pub size: u32,
+    pub records: Vec<RaceRecord>,
+}
+
+/// A run flagged by the server telemetry checks, kept for admin review.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct FlaggedRun {
+    pub race_id: i64,
+    pub room: String,
+    pub stage: String,
+    pub name: String,
+    pub finishtime: f32,
+    pub reason: String,
+    pub created_at: String,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RaceDetail {
+    pub race_id: i64,
+    pub info: RaceInfo,
+    pub finished_at: String,
+    pub results: Vec<MetaRaceResult>,
+}
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StageRecordQuery {
+    pub car: Option<String>,
+    pub car_group: Option<String>,
+    pub surface: Option<String>,
+    pub weather: Option<String>,
+    pub limit: u32,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct StageRecord {
+    pub race_id: i64,
+    pub name: String,
+    pub car: String,
+    pub skytype: String,
+    pub time: f32,
+    pub finished_at: String,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct StageRecords {
+    pub stage_id: u32,
+    pub fastest: Vec<StageRecord>,
+    pub split1: Option<StageRecord>,
+    pub split2: Option<StageRecord>,
+    pub personal_bests: Vec<StageRecord>,
+}
+
+/// JSON text frames of the room live timing websocket `/api/race/live/{room}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum LiveTiming {
+    RoomState(RoomState),
+    RaceState(Vec<RaceUserState>),
+    RaceProgress(Vec<MetaRaceProgress>),
+    RaceResult(Vec<MetaRaceResult>),
+}
@@ -5,6 +5,15 @@ pub mod metaapi;
 pub mod rsfdata;
 
 pub static API_VERSION_STRING: &'static str = std::env!("CARGO_PKG_VERSION");
+pub static API_COMPAT_RANGE: &str = ">=2.6.0, <3.0.0";
+
+/// Check a peer's protocol version against the range this build can talk to.
+pub fn is_version_compatible(version: &str) -> bool {
+    if let (Ok(version), Ok(range)) = (semver::Version::parse(version), semver::VersionReq::parse(API_COMPAT_RANGE)) {
+        return range.matches(&version);
+    }
+    false
+}
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 #[repr(C, packed)]
@@ -1,3 +1,7 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use crate::{httpapi::RaceInfo, D3DQuaternion};
 use super::httpapi::RaceState;
@@ -8,7 +12,8 @@ pub enum RaceCmd {
     RaceCmdDefault,
     RaceCmdPrepare(RaceInfo),
     RaceCmdLoad,
-    RaceCmdStart,
+    /// Release the handbrake at this server instant, unix milliseconds.
+    RaceCmdStart(i64),
     RaceCmdUpload,
     RaceCmdFinish,
     RaceCmdHorn,
@@ -27,6 +32,10 @@ pub enum DataFormat {
     FmtSyncRaceResult = 7,
     FmtSyncRaceNotice = 8,
     FmtSyncRaceRidicule = 9,
+    FmtHandshake = 10,
+    FmtPing = 11,
+    FmtPong = 12,
+    FmtKeepAlive = 13,
     FmtResponse = 0x8000,
 }
 
@@ -40,6 +49,95 @@ pub struct RaceJoin {
     pub passwd: Option<String>,
 }
 
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MetaHandshake {
+    pub version: String,
+    pub accepted: bool,
+    pub notice: String,
+}
+
+///
+/// Meta protocol revisions of the 2.x line, both sides announce their version
+/// in the handshake and only send the frames and fields the peer knows.
+///
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MetaRevision {
+    /// 2.6: handshake and length checked frames.
+    #[default]
+    Handshake,
+    /// 2.7: the start command carries a server instant, ping and pong frames.
+    TimedStart,
+    /// 2.8: keepalive frames.
+    KeepAlive,
+    /// 2.9: gaps to the cars ahead and behind in the race progress.
+    TimeGaps,
+    /// 2.10: race results sent in pages.
+    ResultPages,
+}
+
+impl MetaRevision {
+    pub fn of(version: &str) -> Self {
+        match semver::Version::parse(version) {
+            Ok(version) if version.major > 2 => Self::ResultPages,
+            Ok(version) if version.major == 2 => match version.minor {
+                0..=6 => Self::Handshake,
+                7 => Self::TimedStart,
+                8 => Self::KeepAlive,
+                9 => Self::TimeGaps,
+                _ => Self::ResultPages,
+            },
+            _ => Self::Handshake,
+        }
+    }
+}
+
+/// Race commands before `MetaRevision::TimedStart`, the start had no instant.
+#[derive(Deserialize)]
+enum LegacyRaceCmd {
+    Default,
+    Prepare(RaceInfo),
+    Load,
+    Start,
+    Upload,
+    Finish,
+    Horn,
+}
+
+/// Decode a race command of a server at the revision, an instant-less start means now.
+pub fn decode_race_cmd(pack_data: &[u8], revision: MetaRevision) -> Result<RaceCmd, MetaCodecError> {
+    if revision >= MetaRevision::TimedStart {
+        return decode_body(pack_data);
+    }
+
+    Ok(match decode_body::<LegacyRaceCmd>(pack_data)? {
+        LegacyRaceCmd::Default => RaceCmd::RaceCmdDefault,
+        LegacyRaceCmd::Prepare(info) => RaceCmd::RaceCmdPrepare(info),
+        LegacyRaceCmd::Load => RaceCmd::RaceCmdLoad,
+        LegacyRaceCmd::Start => RaceCmd::RaceCmdStart(unix_millis()),
+        LegacyRaceCmd::Upload => RaceCmd::RaceCmdUpload,
+        LegacyRaceCmd::Finish => RaceCmd::RaceCmdFinish,
+        LegacyRaceCmd::Horn => RaceCmd::RaceCmdHorn,
+    })
+}
+
+/// Sent by the client with its own clock, the server stamps its clock on the pong.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MetaPing {
+    pub client_time: i64,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MetaPong {
+    pub client_time: i64,
+    pub server_time: i64,
+}
+
+/// Sent by the server every keepalive tick and echoed back unchanged by the client.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MetaKeepAlive {
+    pub server_time: i64,
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct RaceAccess {
     pub token: String,
@@ -59,14 +157,141 @@ pub struct RaceUpdate {
     pub state: RaceState,
 }
 
-pub static META_HEADER_LEN: usize = 6;
+pub static META_HEADER_LEN: usize = 8;
+pub static META_MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Entries the game overlay holds of the race progress and of a result page.
+pub const RACE_VIEW_SIZE: usize = 8;
+/// Entries the game overlay holds of the race state.
+pub const RACE_STATE_VIEW_SIZE: usize = 16;
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct MetaHeader {
-    pub length: u16,
+    pub length: u32,
     pub format: DataFormat,
 }
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[derive(Debug)]
+pub enum MetaCodecError {
+    InvalidHeader,
+    FrameTooLarge(usize),
+    InvalidBody(String),
+}
+
+impl fmt::Display for MetaCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetaCodecError::InvalidHeader => write!(f, "invalid meta frame header"),
+            MetaCodecError::FrameTooLarge(len) => write!(f, "meta frame length {} exceeds limit {}", len, META_MAX_FRAME_LEN),
+            MetaCodecError::InvalidBody(err) => write!(f, "invalid meta frame body: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MetaCodecError {}
+
+///
+/// Encode one body into a length prefixed meta frame.
+///
+pub fn encode_frame<T: Serialize>(format: DataFormat, body: &T) -> Result<Vec<u8>, MetaCodecError> {
+    let body = bincode::serialize(body).map_err(|e| MetaCodecError::InvalidBody(e.to_string()))?;
+    if body.len() > META_MAX_FRAME_LEN {
+        return Err(MetaCodecError::FrameTooLarge(body.len()));
+    }
+
+    let head = bincode::serialize(&MetaHeader {length: body.len() as u32, format})
+        .map_err(|_| MetaCodecError::InvalidHeader)?;
+    Ok([&head[..], &body[..]].concat())
+}
+
+pub fn decode_body<T: DeserializeOwned>(pack_data: &[u8]) -> Result<T, MetaCodecError> {
+    bincode::deserialize(pack_data).map_err(|e| MetaCodecError::InvalidBody(e.to_string()))
+}
+
+///
+/// Reassemble meta frames from a byte stream, any error means the peer is
+/// broken and the connection should be dropped.
+///
+#[derive(Default)]
+pub struct MetaDecoder {
+    remain: Vec<u8>,
+}
+
+impl MetaDecoder {
+    pub fn feed(&mut self, data: &[u8]) {
+        self.remain.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<(MetaHeader, Vec<u8>)>, MetaCodecError> {
+        if self.remain.len() < META_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let head: MetaHeader = bincode::deserialize(&self.remain[..META_HEADER_LEN])
+            .map_err(|_| MetaCodecError::InvalidHeader)?;
+        let length = head.length as usize;
+        if length > META_MAX_FRAME_LEN {
+            return Err(MetaCodecError::FrameTooLarge(length));
+        }
+
+        if self.remain.len() < META_HEADER_LEN + length {
+            return Ok(None);
+        }
+        let pack_data = self.remain[META_HEADER_LEN..META_HEADER_LEN + length].to_vec();
+        self.remain.drain(..META_HEADER_LEN + length);
+        Ok(Some((head, pack_data)))
+    }
+}
+
+/// Wall clock in unix milliseconds, the time base of ping, pong and the race start.
+pub fn unix_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as i64).unwrap_or_default()
+}
+
+static CLOCK_SAMPLES: usize = 8;
+
+///
+/// Clock offset and round trip time to the server from ping/pong rounds,
+/// the round of the recent ones with the lowest RTT is the most accurate.
+///
+#[derive(Default, Clone, Debug)]
+pub struct ClockSync {
+    samples: VecDeque<(i64, i64)>,
+}
+
+impl ClockSync {
+    pub fn feed(&mut self, pong: &MetaPong, now: i64) {
+        let rtt = now - pong.client_time;
+        if rtt < 0 {
+            return;
+        }
+
+        let offset = pong.server_time - (pong.client_time + now) / 2;
+        if self.samples.len() == CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt, offset));
+    }
+
+    fn best(&self) -> Option<&(i64, i64)> {
+        self.samples.iter().min_by_key(|(rtt, _)| *rtt)
+    }
+
+    pub fn get_rtt(&self) -> Option<i64> {
+        self.best().map(|(rtt, _)| *rtt)
+    }
+
+    /// Server clock minus local clock in milliseconds.
+    pub fn get_offset(&self) -> Option<i64> {
+        self.best().map(|(_, offset)| *offset)
+    }
+
+    /// Convert a server instant to the local clock, unchanged before the first pong.
+    pub fn to_local(&self, server_time: i64) -> i64 {
+        server_time - self.get_offset().unwrap_or_default()
+    }
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct MetaRaceState {
     pub name: String,
     pub state: RaceState,
@@ -90,6 +315,20 @@ pub struct MetaRaceData {
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct MetaRaceProgress {
+    pub profile_name: String,
+    pub progress: f32,
+    pub difffirst: f32,
+    /// Seconds behind the car ahead at the same distance.
+    pub diffahead: f32,
+    /// Seconds the car behind trails at the same distance.
+    pub diffbehind: f32,
+    pub carlook: D3DQuaternion,
+    pub carpos: D3DQuaternion,
+}
+
+/// Race progress entry before `MetaRevision::TimeGaps`.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct LegacyRaceProgress {
     pub profile_name: String,
     pub progress: f32,
     pub difffirst: f32,
@@ -97,6 +336,40 @@ pub struct MetaRaceProgress {
     pub carpos: D3DQuaternion,
 }
 
+impl From<&MetaRaceProgress> for LegacyRaceProgress {
+    fn from(item: &MetaRaceProgress) -> Self {
+        Self {
+            profile_name: item.profile_name.clone(),
+            progress: item.progress,
+            difffirst: item.difffirst,
+            carlook: item.carlook.clone(),
+            carpos: item.carpos.clone(),
+        }
+    }
+}
+
+impl From<LegacyRaceProgress> for MetaRaceProgress {
+    fn from(item: LegacyRaceProgress) -> Self {
+        Self {
+            profile_name: item.profile_name,
+            progress: item.progress,
+            difffirst: item.difffirst,
+            carlook: item.carlook,
+            carpos: item.carpos,
+            ..Default::default()
+        }
+    }
+}
+
+/// Decode the race progress of a server at the revision.
+pub fn decode_race_progress(pack_data: &[u8], revision: MetaRevision) -> Result<Vec<MetaRaceProgress>, MetaCodecError> {
+    if revision >= MetaRevision::TimeGaps {
+        return decode_body(pack_data);
+    }
+    let progress: Vec<LegacyRaceProgress> = decode_body(pack_data)?;
+    Ok(progress.into_iter().map(MetaRaceProgress::from).collect())
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct MetaRaceRidicule {
     pub players: Vec<String>,
@@ -111,4 +384,170 @@ pub struct MetaRaceResult {
     pub finishtime: f32,
     pub difftime: f32,
     pub score: i32,
-}
\ No newline at end of file
+}
+
+/// One page of the race standings, pages are sent in order starting from 0.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MetaRaceResultPage {
+    pub page: u32,
+    pub pages: u32,
+    pub results: Vec<MetaRaceResult>,
+}
+
+/// Decode the race results of a server at the revision, older servers send all results at once.
+pub fn decode_race_result(pack_data: &[u8], revision: MetaRevision) -> Result<MetaRaceResultPage, MetaCodecError> {
+    if revision >= MetaRevision::ResultPages {
+        return decode_body(pack_data);
+    }
+    Ok(MetaRaceResultPage { page: 0, pages: 1, results: decode_body(pack_data)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revision_follows_the_minor_version() {
+        assert_eq!(MetaRevision::of("2.6.0"), MetaRevision::Handshake);
+        assert_eq!(MetaRevision::of("2.7.3"), MetaRevision::TimedStart);
+        assert_eq!(MetaRevision::of("2.9.0"), MetaRevision::TimeGaps);
+        assert_eq!(MetaRevision::of("2.14.0"), MetaRevision::ResultPages);
+        assert_eq!(MetaRevision::of("broken"), MetaRevision::Handshake);
+    }
+
+    #[test]
+    fn legacy_bodies_decode() {
+        let progress = vec![LegacyRaceProgress { profile_name: "a".to_string(), progress: 10.0, difffirst: 1.5, ..Default::default() }; 2];
+        let body = bincode::serialize(&progress).unwrap();
+        let decoded = decode_race_progress(&body, MetaRevision::KeepAlive).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1].difffirst, 1.5);
+
+        let results = vec![MetaRaceResult::default(); 12];
+        let page = decode_race_result(&bincode::serialize(&results).unwrap(), MetaRevision::TimeGaps).unwrap();
+        assert_eq!((page.page, page.pages, page.results.len()), (0, 1, 12));
+
+        let start = bincode::serialize(&3u32).unwrap(); // the unit start variant.
+        assert!(matches!(decode_race_cmd(&start, MetaRevision::Handshake).unwrap(), RaceCmd::RaceCmdStart(_)));
+    }
+
+    fn state(name: &str) -> MetaRaceState {
+        MetaRaceState { name: name.to_string(), state: RaceState::RaceRunning }
+    }
+
+    #[test]
+    fn frame_split_across_feeds() {
+        let frame = encode_frame(DataFormat::FmtSyncRaceState, &state("alice")).unwrap();
+        let mut decoder = MetaDecoder::default();
+        decoder.feed(&frame[..5]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.feed(&frame[5..12]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.feed(&frame[12..]);
+
+        let (head, body) = decoder.next_frame().unwrap().unwrap();
+        assert!(matches!(head.format, DataFormat::FmtSyncRaceState));
+        assert_eq!(decode_body::<MetaRaceState>(&body).unwrap(), state("alice"));
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn several_frames_in_one_feed() {
+        let mut stream = encode_frame(DataFormat::FmtSyncRaceState, &state("alice")).unwrap();
+        stream.extend(encode_frame(DataFormat::FmtRaceCommand, &RaceCmd::RaceCmdLoad).unwrap());
+        stream.extend(encode_frame(DataFormat::FmtSyncRaceState, &state("bob")).unwrap());
+        let mut decoder = MetaDecoder::default();
+        decoder.feed(&stream);
+
+        let (_, body) = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(decode_body::<MetaRaceState>(&body).unwrap(), state("alice"));
+        let (head, _) = decoder.next_frame().unwrap().unwrap();
+        assert!(matches!(head.format, DataFormat::FmtRaceCommand));
+        let (_, body) = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(decode_body::<MetaRaceState>(&body).unwrap(), state("bob"));
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let head = MetaHeader { length: META_MAX_FRAME_LEN as u32 + 1, format: DataFormat::FmtSyncRaceData };
+        let mut decoder = MetaDecoder::default();
+        decoder.feed(&bincode::serialize(&head).unwrap());
+        assert!(matches!(decoder.next_frame(), Err(MetaCodecError::FrameTooLarge(_))));
+
+        let body = vec![0u8; META_MAX_FRAME_LEN];
+        assert!(matches!(encode_frame(DataFormat::FmtSyncRaceData, &body), Err(MetaCodecError::FrameTooLarge(_))));
+    }
+
+    #[test]
+    fn truncated_body_is_rejected() {
+        let body = bincode::serialize(&state("alice")).unwrap();
+        assert!(matches!(decode_body::<MetaRaceState>(&body[..body.len() - 2]), Err(MetaCodecError::InvalidBody(_))));
+
+        // a header claiming less than the body holds cuts the body short.
+        let head = MetaHeader { length: 6, format: DataFormat::FmtSyncRaceState };
+        let mut decoder = MetaDecoder::default();
+        decoder.feed(&bincode::serialize(&head).unwrap());
+        decoder.feed(&body);
+        let (_, truncated) = decoder.next_frame().unwrap().unwrap();
+        assert!(decode_body::<MetaRaceState>(&truncated).is_err());
+    }
+
+    /// Feed a round sent at client_time and answered at server_time, back after rtt.
+    fn round(clock: &mut ClockSync, client_time: i64, server_time: i64, rtt: i64) {
+        clock.feed(&MetaPong { client_time, server_time }, client_time + rtt);
+    }
+
+    #[test]
+    fn clock_is_unchanged_before_the_first_pong() {
+        let clock = ClockSync::default();
+        assert_eq!(clock.get_rtt(), None);
+        assert_eq!(clock.get_offset(), None);
+        assert_eq!(clock.to_local(123_456), 123_456);
+    }
+
+    #[test]
+    fn clock_offset_is_taken_at_half_the_round() {
+        let mut clock = ClockSync::default();
+        round(&mut clock, 1000, 5050, 100);
+        assert_eq!(clock.get_rtt(), Some(100));
+        assert_eq!(clock.get_offset(), Some(4000));
+        assert_eq!(clock.to_local(6000), 2000);
+    }
+
+    #[test]
+    fn clock_follows_the_fastest_round() {
+        let mut clock = ClockSync::default();
+        round(&mut clock, 1000, 5050, 100);
+        round(&mut clock, 2000, 6015, 20);
+        round(&mut clock, 3000, 7100, 60);
+        assert_eq!(clock.get_rtt(), Some(20));
+        assert_eq!(clock.get_offset(), Some(4005));
+    }
+
+    #[test]
+    fn clock_forgets_old_rounds() {
+        let mut clock = ClockSync::default();
+        round(&mut clock, 0, 4005, 10);
+        for i in 1..CLOCK_SAMPLES as i64 {
+            round(&mut clock, i * 1000, i * 1000 + 4025, 50);
+        }
+        assert_eq!(clock.get_rtt(), Some(10));
+
+        round(&mut clock, 9000, 13025, 50);
+        assert_eq!(clock.get_rtt(), Some(50));
+        assert_eq!(clock.get_offset(), Some(4000));
+    }
+
+    #[test]
+    fn clock_rejects_negative_rtt() {
+        let mut clock = ClockSync::default();
+        round(&mut clock, 1000, 5000, -5);
+        assert_eq!(clock.get_rtt(), None);
+
+        round(&mut clock, 2000, 6050, 100);
+        round(&mut clock, 3000, 9000, -1);
+        assert_eq!(clock.get_rtt(), Some(100));
+        assert_eq!(clock.get_offset(), Some(4000));
+    }
+}
@@ -1,6 +1,6 @@
 use libc::{c_uchar, c_float, c_uint};
 use crate::httpapi::{RaceInfo, RaceConfig};
-use crate::metaapi::{MetaRaceProgress, MetaRaceResult, MetaRaceState};
+use crate::metaapi::{MetaRaceProgress, MetaRaceResult, MetaRaceState, RACE_STATE_VIEW_SIZE, RACE_VIEW_SIZE};
 use crate::{D3DMatrix, D3DQuaternion};
 use serde::{Serialize, Deserialize};
 use std::mem::size_of;
@@ -163,7 +163,7 @@ pub struct RBRRaceStateItem {
 pub struct RBRRaceState {
     pub external: c_uint,
     pub count: c_uint,
-    pub data: [RBRRaceStateItem; 16],
+    pub data: [RBRRaceStateItem; RACE_STATE_VIEW_SIZE],
 }
 
 impl RBRRaceState {
@@ -171,7 +171,7 @@ impl RBRRaceState {
         let mut racestate = RBRRaceState::default();
         racestate.external = 1;
         for (index, item) in result.iter().enumerate() {
-            if index >= 16 {
+            if index >= RACE_STATE_VIEW_SIZE {
                 break;
             }
 
@@ -216,7 +216,7 @@ pub struct RBRRaceItem {
 pub struct RBRRaceData {
     pub external: c_uint,
     pub count: c_uint,
-    pub data: [RBRRaceItem; 8],
+    pub data: [RBRRaceItem; RACE_VIEW_SIZE],
 }
 
 impl RBRRaceData {
@@ -224,7 +224,7 @@ impl RBRRaceData {
         let mut racedata = RBRRaceData::default();
         racedata.external = 1;
         for (index, item) in result.iter().enumerate() {
-            if index >= 8 {
+            if index >= RACE_VIEW_SIZE {
                 break;
             }
 
@@ -282,7 +282,7 @@ pub struct RBRRaceResultItem {
 pub struct RBRRaceResult {
     pub external: c_uint,
     pub count: c_uint,
-    pub data: [RBRRaceResultItem; 8],
+    pub data: [RBRRaceResultItem; RACE_VIEW_SIZE],
 }
 
 impl RBRRaceResult {
@@ -290,7 +290,7 @@ impl RBRRaceResult {
         let mut raceresult = RBRRaceResult::default();
         raceresult.external = 1;
         for (index, item) in result.iter().enumerate() {
-            if index >= 8 {
+            if index >= RACE_VIEW_SIZE {
                 break;
             }
 
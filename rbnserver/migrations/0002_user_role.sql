@@ -0,0 +1 @@
+ALTER TABLE user ADD COLUMN role TEXT NOT NULL default 'Player';
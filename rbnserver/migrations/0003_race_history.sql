@@ -0,0 +1,34 @@
+CREATE TABLE IF NOT EXISTS race (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    room TEXT NOT NULL,
+    stage TEXT NOT NULL,
+    stage_id INTEGER NOT NULL,
+    stage_type TEXT NOT NULL,
+    stage_len INTEGER NOT NULL,
+    car_fixed BOOLEAN NOT NULL,
+    car TEXT NOT NULL,
+    car_id INTEGER NOT NULL,
+    damage INTEGER NOT NULL,
+    weather INTEGER NOT NULL,
+    wetness INTEGER NOT NULL,
+    skytype TEXT NOT NULL,
+    skytype_id INTEGER NOT NULL,
+    players INTEGER NOT NULL,
+    finished_at TEXT NOT NULL default (datetime('now', 'localtime'))
+);
+
+CREATE TABLE IF NOT EXISTS race_result (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    race_id INTEGER NOT NULL REFERENCES race (id),
+    name TEXT NOT NULL,
+    racecar TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    splittime1 REAL NOT NULL,
+    splittime2 REAL NOT NULL,
+    finishtime REAL NOT NULL,
+    difftime REAL NOT NULL,
+    score INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS race_result_name_index ON race_result (name);
+CREATE INDEX IF NOT EXISTS race_result_race_index ON race_result (race_id);
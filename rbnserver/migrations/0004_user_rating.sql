@@ -0,0 +1,19 @@
+ALTER TABLE user ADD COLUMN rating REAL NOT NULL default 1500.0;
+ALTER TABLE user ADD COLUMN deviation REAL NOT NULL default 350.0;
+ALTER TABLE user ADD COLUMN volatility REAL NOT NULL default 0.06;
+
+-- license is derived from the rating now, everyone starts over.
+UPDATE user SET license = 'Rookie';
+
+CREATE TABLE IF NOT EXISTS rating_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    race_id INTEGER NOT NULL REFERENCES race (id),
+    name TEXT NOT NULL,
+    rating REAL NOT NULL,
+    deviation REAL NOT NULL,
+    volatility REAL NOT NULL,
+    delta REAL NOT NULL,
+    created_at TEXT NOT NULL default (datetime('now', 'localtime'))
+);
+
+CREATE INDEX IF NOT EXISTS rating_history_name_index ON rating_history (name);
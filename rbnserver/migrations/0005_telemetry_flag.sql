@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS telemetry_flag (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    race_id INTEGER NOT NULL REFERENCES race (id),
+    name TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TEXT NOT NULL default (datetime('now', 'localtime'))
+);
+
+CREATE INDEX IF NOT EXISTS telemetry_flag_race_index ON telemetry_flag (race_id);
@@ -0,0 +1,65 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpRequest};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use rbnproto::httpapi::{UserRegister, PASSWD_MIN_LEN};
+use uuid::Uuid;
+use crate::player::LobbyPlayer;
+use crate::server::RacingServer;
+
+pub static TOKEN_EXPIRE_HOURS: i64 = 24;
+pub static REFRESH_EXPIRE_DAYS: i64 = 7;
+
+pub fn hash_passwd(passwd: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(passwd.as_bytes(), &salt).ok().map(|x| x.to_string())
+}
+
+pub fn verify_passwd(passwd: &str, hashed: &str) -> bool {
+    if let Ok(parsed) = PasswordHash::new(hashed) {
+        return Argon2::default().verify_password(passwd.as_bytes(), &parsed).is_ok();
+    }
+    false
+}
+
+pub fn is_register_valid(user: &UserRegister) -> bool {
+    !user.name.is_empty() && user.passwd.len() >= PASSWD_MIN_LEN
+}
+
+/// One-time code for an admin reset, only its hash is stored.
+pub fn new_claim_code() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// Logged in player resolved from the `Authorization: Bearer <token>` header,
+/// requests with a missing, expired or revoked token are rejected with 401.
+pub struct AuthPlayer(pub LobbyPlayer);
+
+impl FromRequest for AuthPlayer {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let data = req.app_data::<web::Data<Arc<RacingServer>>>().cloned();
+        Box::pin(async move {
+            if let (Some(token), Some(data)) = (token, data) {
+                if let Some(player) = data.authorize(&token).await {
+                    return Ok(AuthPlayer(player));
+                }
+            }
+            Err(ErrorUnauthorized("Invalid or expired token!"))
+        })
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    header.strip_prefix("Bearer ").map(|x| x.trim().to_string())
+}
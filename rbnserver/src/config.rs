@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use rbnproto::httpapi::RaceScoring;
+use serde::{Deserialize, Serialize};
+
+static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
+
+/// Loaded once at startup, every module reads the same settings afterwards.
+pub fn init(config: ServerConfig) {
+    let _ = CONFIG.set(config);
+}
+
+pub fn get() -> &'static ServerConfig {
+    CONFIG.get_or_init(ServerConfig::default)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub http_port: u16,
+    pub meta_port: u16,
+    /// Most players in a custom room, the room owner may pick fewer.
+    pub room_limit: u32,
+    pub paths: PathConfig,
+    pub tick: TickConfig,
+    pub timeout: TimeoutConfig,
+    pub telemetry: TelemetryConfig,
+    pub series: Vec<SeriesConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_port: 23555,
+            meta_port: 23556,
+            room_limit: 64,
+            paths: PathConfig::default(),
+            tick: TickConfig::default(),
+            timeout: TimeoutConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            series: vec![
+                SeriesConfig::named("Daily Challenge", RaceScoring::WrcPoints),
+                SeriesConfig::named("Time Trial", RaceScoring::TimeBased),
+                SeriesConfig::named("Practice", RaceScoring::NoScore),
+            ],
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(file: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        let mut config: ServerConfig = toml::from_str(&text).map_err(|e| format!("{}: {}", file.display(), e))?;
+        if let Some(base) = file.parent() {
+            config.paths.rebase(base);
+        }
+        for series in &config.series {
+            cron::Schedule::from_str(&series.schedule).map_err(|e| format!("series {}: {}", series.name, e))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Unset paths keep the historic layout next to the executable,
+/// relative paths in a config file are resolved against the file itself.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathConfig {
+    pub data_dir: Option<PathBuf>,
+    pub db_file: Option<PathBuf>,
+    pub template_dir: Option<PathBuf>,
+    pub download_dir: Option<PathBuf>,
+}
+
+fn exe_dir() -> PathBuf {
+    std::env::current_exe().unwrap().parent().unwrap().to_path_buf()
+}
+
+impl PathConfig {
+    fn rebase(&mut self, base: &Path) {
+        for path in [&mut self.data_dir, &mut self.db_file, &mut self.template_dir, &mut self.download_dir].into_iter().flatten() {
+            if path.is_relative() {
+                *path = base.join(&path);
+            }
+        }
+    }
+
+    pub fn get_data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(|| exe_dir().join("rsfdata"))
+    }
+
+    pub fn get_db_file(&self) -> PathBuf {
+        self.db_file.clone().unwrap_or_else(|| exe_dir().join("rbndata.db"))
+    }
+
+    pub fn get_template_dir(&self) -> PathBuf {
+        self.template_dir.clone().unwrap_or_else(|| PathBuf::from("templates"))
+    }
+
+    pub fn get_asset_dir(&self) -> PathBuf {
+        self.template_dir.clone().unwrap_or_else(|| exe_dir().join("templates")).join("assets")
+    }
+
+    pub fn get_download_dir(&self) -> PathBuf {
+        self.download_dir.clone().unwrap_or_else(|| exe_dir().join("downloads"))
+    }
+}
+
+/// Intervals in milliseconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TickConfig {
+    /// Room state machines and recycling.
+    pub schedule: u64,
+    /// Pit notices of the next scheduled race.
+    pub notice: u64,
+    /// In race ranking and ridicule checks.
+    pub ranking: u64,
+    /// Lead time of the synchronized race start, covers the start command delivery.
+    pub start: u64,
+    /// Keepalive frames on meta connections, their echo measures the latency.
+    pub keepalive: u64,
+}
+
+impl Default for TickConfig {
+    fn default() -> Self {
+        Self { schedule: 50, notice: 500, ranking: 1000, start: 3000, keepalive: 1000 }
+    }
+}
+
+/// Race phase deadlines in seconds, players missing the deadline before the
+/// start are dropped to the pit, players stuck in the race are retired.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub prepare: i64,
+    pub loading: i64,
+    pub starting: i64,
+    /// No race data received from a running player.
+    pub stalled: i64,
+    /// Grace period for a running player to resume a lost meta connection.
+    pub reconnect: i64,
+    /// Meta connection silent or stuck writing this long, dropped as dead.
+    pub heartbeat: i64,
+    pub exiting: i64,
+    /// Longest plausible race as a multiple of the stage default time.
+    pub running_factor: f32,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self { prepare: 60, loading: 120, starting: 60, stalled: 30, reconnect: 60, heartbeat: 5, exiting: 60, running_factor: 3.0 }
+    }
+}
+
+/// Plausibility limits of the uploaded race data, runs breaking them are
+/// flagged for review and left out of the scoring.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// Top speed in km/h.
+    pub max_speed: f32,
+    /// Meters the progress may go back, driving back after going off the road.
+    pub progress_backoff: f32,
+    /// Meters allowed beyond what the reported speed covers between two frames.
+    pub distance_slack: f32,
+    /// Seconds the race clock may drift from the server clock.
+    pub clock_slack: f32,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self { enabled: true, max_speed: 400.0, progress_backoff: 200.0, distance_slack: 30.0, clock_slack: 15.0 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeriesConfig {
+    pub name: String,
+    /// Cron expression with seconds, when the next stage starts.
+    pub schedule: String,
+    /// Fixed damage level, random when unset.
+    pub damage: Option<u32>,
+    pub scoring: RaceScoring,
+    pub randomer: RandomerConfig,
+}
+
+impl Default for SeriesConfig {
+    fn default() -> Self {
+        let schedule = if cfg!(debug_assertions) { "0/30 * * * * *" } else { "0 0/2 * * * *" };
+        Self {
+            name: String::new(),
+            schedule: schedule.to_string(),
+            damage: Some(3),
+            scoring: RaceScoring::default(),
+            randomer: RandomerConfig::default(),
+        }
+    }
+}
+
+impl SeriesConfig {
+    fn named(name: &str, scoring: RaceScoring) -> Self {
+        Self { name: name.to_string(), scoring, ..Default::default() }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RandomerConfig {
+    /// Skip stages listed in `stages_exclude.json`.
+    pub exclude: bool,
+    pub stage: Option<String>,
+    pub car: Option<String>,
+    /// Always race in the default weather.
+    pub fixed_weather: bool,
+}
+
+impl Default for RandomerConfig {
+    fn default() -> Self {
+        // debug builds race one short stage and car to test quickly.
+        let (stage, car) = match cfg!(debug_assertions) {
+            true => (Some("Lyon - Gerland".to_string()), Some("Hyundai i20 Coupe WRC 2021".to_string())),
+            false => (None, None),
+        };
+        Self { exclude: true, stage, car, fixed_weather: false }
+    }
+}
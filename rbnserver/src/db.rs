@@ -1,8 +1,13 @@
 use std::path::PathBuf;
-use rbnproto::{httpapi::UserScore, metaapi::MetaRaceResult};
-use sqlx::SqlitePool;
+use rbnproto::httpapi::{FlaggedRun, RaceDetail, RaceHistory, RaceInfo, RaceRecord, RatingRecord, StageRecord, StageRecordQuery, StageRecords, UserRating, UserScore};
+use rbnproto::metaapi::MetaRaceResult;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
 
-use crate::player::LobbyPlayer;
+use crate::auth;
+use crate::player::UserRole;
+use crate::rating::{self, Rating};
+use crate::series::telemetry::TelemetryFlag;
 
 #[allow(dead_code)]
 #[derive(sqlx::FromRow)]
@@ -12,116 +17,558 @@ struct User {
     passwd: String,
     license: String,
     score: i32,
+    role: String,
+    rating: f64,
+    deviation: f64,
+    volatility: f64,
+    /// Hash of the one-time code of an admin reset.
+    claim: Option<String>,
 }
 
-pub struct RaceDB {
-    dbfile: PathBuf,
+impl User {
+    fn get_rating(&self) -> Rating {
+        Rating { rating: self.rating, deviation: self.deviation, volatility: self.volatility }
+    }
 }
 
-impl Default for RaceDB {
-    fn default() -> Self {
-        let file = std::env::current_exe().unwrap().parent().unwrap().join("rbndata.db");
-        Self { 
-            dbfile: file,
+impl From<User> for UserScore {
+    fn from(user: User) -> Self {
+        UserScore {
+            name: user.name,
+            license: user.license,
+            score: user.score,
+            rating: user.rating,
+            deviation: user.deviation,
         }
     }
 }
 
-impl RaceDB {
-    pub async fn migrate(&mut self) {
-        if !self.dbfile.exists() {
-            std::fs::File::create(self.dbfile.clone()).expect("Failed to create database file.");
+#[derive(sqlx::FromRow)]
+struct RatingRecordRow {
+    race_id: i64,
+    rating: f64,
+    deviation: f64,
+    delta: f64,
+    created_at: String,
+}
+
+impl From<RatingRecordRow> for RatingRecord {
+    fn from(row: RatingRecordRow) -> Self {
+        RatingRecord {
+            race_id: row.race_id,
+            rating: row.rating,
+            deviation: row.deviation,
+            delta: row.delta,
+            created_at: row.created_at,
         }
-    
-        let pool = SqlitePool::connect(self.dbfile.to_str().unwrap()).await.unwrap();
-        sqlx::migrate!().run(&pool).await.unwrap();
-    }
-
-    fn get_license(&mut self, score: i32) -> String {
-        let mut license = String::from("Rookie");
-        if score < 500 {
-            license = "Rookie".to_string();
-        } else if 500 <= score && score < 1000 {
-            license = "Amateur".to_string();
-        } else if 1000 <= score && score < 1500 {
-            license = "Master".to_string();
-        } else if 1500 <= score && score < 2000 {
-            license = "Profession".to_string();
-        } else if 2000 <= score {
-            license = "Buglike".to_string();
+    }
+}
+
+#[allow(dead_code)]
+#[derive(sqlx::FromRow)]
+struct Race {
+    id: i64,
+    room: String,
+    stage: String,
+    stage_id: u32,
+    stage_type: String,
+    stage_len: u32,
+    car_fixed: bool,
+    car: String,
+    car_id: u32,
+    damage: u32,
+    weather: u32,
+    wetness: u32,
+    skytype: String,
+    skytype_id: u32,
+    players: u32,
+    finished_at: String,
+}
+
+impl From<Race> for RaceInfo {
+    fn from(race: Race) -> Self {
+        RaceInfo {
+            name: race.room,
+            owner: String::new(),
+            stage: race.stage,
+            stage_id: race.stage_id,
+            stage_type: race.stage_type,
+            stage_len: race.stage_len,
+            car_fixed: race.car_fixed,
+            car: race.car,
+            car_id: race.car_id,
+            damage: race.damage,
+            weather: race.weather,
+            wetness: race.wetness,
+            skytype: race.skytype,
+            skytype_id: race.skytype_id,
         }
+    }
+}
 
-        license
+#[allow(dead_code)]
+#[derive(sqlx::FromRow)]
+struct RaceResultRow {
+    id: i64,
+    race_id: i64,
+    name: String,
+    racecar: String,
+    position: u32,
+    splittime1: f32,
+    splittime2: f32,
+    finishtime: f32,
+    difftime: f32,
+    score: i32,
+}
+
+impl From<RaceResultRow> for MetaRaceResult {
+    fn from(row: RaceResultRow) -> Self {
+        MetaRaceResult {
+            profile_name: row.name,
+            racecar: row.racecar,
+            splittime1: row.splittime1,
+            splittime2: row.splittime2,
+            finishtime: row.finishtime,
+            difftime: row.difftime,
+            score: row.score,
+        }
     }
+}
 
-    pub async fn connect(&mut self) -> SqlitePool {
-        SqlitePool::connect(self.dbfile.to_str().unwrap()).await.unwrap()
+#[derive(sqlx::FromRow)]
+struct RaceRecordRow {
+    race_id: i64,
+    room: String,
+    stage: String,
+    car: String,
+    skytype: String,
+    players: u32,
+    position: u32,
+    splittime1: f32,
+    splittime2: f32,
+    finishtime: f32,
+    difftime: f32,
+    score: i32,
+    finished_at: String,
+}
+
+impl From<RaceRecordRow> for RaceRecord {
+    fn from(row: RaceRecordRow) -> Self {
+        RaceRecord {
+            race_id: row.race_id,
+            room: row.room,
+            stage: row.stage,
+            car: row.car,
+            skytype: row.skytype,
+            players: row.players,
+            position: row.position,
+            splittime1: row.splittime1,
+            splittime2: row.splittime2,
+            finishtime: row.finishtime,
+            difftime: row.difftime,
+            score: row.score,
+            finished_at: row.finished_at,
+        }
     }
+}
 
-    pub async fn query_user_score(&mut self, player: &LobbyPlayer) -> Option<UserScore> {
-        let conn = self.connect().await;
-        let user: Option<User> = sqlx::query_as("SELECT * FROM user WHERE name = ?")
-        .bind(&player.profile_name)
-        .fetch_optional(&conn)
-        .await.unwrap_or_default();
+#[derive(sqlx::FromRow)]
+struct StageRecordRow {
+    race_id: i64,
+    name: String,
+    car: String,
+    skytype: String,
+    time: f32,
+    finished_at: String,
+}
 
-        if let Some(user) = user {
-            return Some(UserScore { name: user.name.clone(), license: user.license.clone(), score: user.score.clone() });
+impl From<StageRecordRow> for StageRecord {
+    fn from(row: StageRecordRow) -> Self {
+        StageRecord {
+            race_id: row.race_id,
+            name: row.name,
+            car: row.car,
+            skytype: row.skytype,
+            time: row.time,
+            finished_at: row.finished_at,
         }
+    }
+}
 
-        None
+#[derive(sqlx::FromRow)]
+struct FlaggedRunRow {
+    race_id: i64,
+    room: String,
+    stage: String,
+    name: String,
+    finishtime: f32,
+    reason: String,
+    created_at: String,
+}
+
+impl From<FlaggedRunRow> for FlaggedRun {
+    fn from(row: FlaggedRunRow) -> Self {
+        FlaggedRun {
+            race_id: row.race_id,
+            room: row.room,
+            stage: row.stage,
+            name: row.name,
+            finishtime: row.finishtime,
+            reason: row.reason,
+            created_at: row.created_at,
+        }
     }
+}
 
-    pub async fn query_all_user_score(&mut self) -> Vec<UserScore> {
-        let conn = self.connect().await;
-        let users: Option<Vec<User>> = sqlx::query_as::<_, User>("SELECT * FROM user order by score desc")
-        .fetch_all(&conn)
-        .await.ok();
+/// Car driven in a result, fixed car rooms leave the player's own config empty.
+static RECORD_CAR: &str = "COALESCE(NULLIF(race_result.racecar, ''), race.car)";
 
-        let mut result = vec![];
-        if let Some(users) = users {
-            for user in users {
-                result.push(UserScore { name: user.name.clone(), license: user.license.clone(), score: user.score.clone() });
-            }
+fn stage_record_query<'a>(column: &str, stage_id: u32, filter: &'a StageRecordQuery, group_cars: &'a [String]) -> QueryBuilder<'a, Sqlite> {
+    let mut builder = QueryBuilder::new(format!("SELECT race.id AS race_id, race_result.name, {} AS car, race.skytype, {} AS time, race.finished_at", RECORD_CAR, column));
+    builder.push(" FROM race_result JOIN race ON race_result.race_id = race.id");
+    builder.push(" LEFT JOIN telemetry_flag ON telemetry_flag.race_id = race_result.race_id AND telemetry_flag.name = race_result.name");
+    builder.push(" WHERE race.stage_id = ").push_bind(stage_id);
+    builder.push(" AND race_result.finishtime > 0 AND race_result.finishtime < 3600"); // skip retired players.
+    builder.push(" AND telemetry_flag.id IS NULL"); // flagged runs never hold a record.
+
+    if let Some(car) = &filter.car {
+        builder.push(format!(" AND {} = ", RECORD_CAR)).push_bind(car);
+    }
+    if filter.car_group.is_some() {
+        builder.push(format!(" AND {} IN (", RECORD_CAR));
+        let mut separated = builder.separated(", ");
+        for car in group_cars {
+            separated.push_bind(car);
         }
+        separated.push_unseparated(")");
+    }
+    if let Some(surface) = &filter.surface {
+        builder.push(" AND race.stage_type = ").push_bind(surface);
+    }
+    if let Some(weather) = &filter.weather {
+        builder.push(" AND race.skytype = ").push_bind(weather);
+    }
+    builder
+}
+
+pub type DbResult<T> = Result<T, sqlx::Error>;
+
+/// Repository over the race database, cheap to clone as every clone shares
+/// the pool opened at startup.
+#[derive(Clone)]
+pub struct RaceDB {
+    pool: SqlitePool,
+}
+
+impl RaceDB {
+    /// Open the pool on the database file and run pending migrations.
+    pub async fn open(dbfile: &PathBuf) -> DbResult<Self> {
+        let options = SqliteConnectOptions::new().filename(dbfile).create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        sqlx::migrate!().run(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    async fn query_user(&self, name: &String) -> DbResult<Option<User>> {
+        sqlx::query_as("SELECT * FROM user WHERE name = ?")
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+    }
 
-        result
+    pub async fn query_user_score(&self, name: &String) -> DbResult<Option<UserScore>> {
+        Ok(self.query_user(name).await?.map(UserScore::from))
     }
 
-    pub async fn on_user_login(&mut self, player: &LobbyPlayer) {
-        let conn = self.connect().await;
+    pub async fn query_all_user_score(&self) -> DbResult<Vec<UserScore>> {
+        let users: Vec<User> = sqlx::query_as::<_, User>("SELECT * FROM user order by rating desc")
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users.into_iter().map(UserScore::from).collect())
+    }
+
+    pub async fn query_user_rating(&self, name: &String) -> DbResult<Option<UserRating>> {
+        let Some(user) = self.query_user(name).await? else {
+            return Ok(None);
+        };
+
+        let history: Vec<RatingRecordRow> = sqlx::query_as("SELECT race_id, rating, deviation, delta, created_at FROM rating_history WHERE name = ? ORDER BY id")
+        .bind(name)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(UserRating {
+            name: user.name,
+            license: user.license,
+            rating: user.rating,
+            deviation: user.deviation,
+            volatility: user.volatility,
+            history: history.into_iter().map(RatingRecord::from).collect(),
+        }))
+    }
+
+    pub async fn query_user_passwd(&self, name: &String) -> DbResult<Option<(String, UserRole)>> {
+        Ok(self.query_user(name).await?.map(|x| (x.passwd, UserRole::from(x.role.as_str()))))
+    }
+
+    /// Register a new user, or set the password of an existing one with the
+    /// one-time code of an admin reset. False when the name is taken.
+    pub async fn on_user_register(&self, name: &String, passwd: &String, claim: Option<&String>) -> DbResult<bool> {
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
         let user: Option<User> = sqlx::query_as("SELECT * FROM user WHERE name = ?")
-        .bind(&player.profile_name)
-        .fetch_optional(&conn)
-        .await.unwrap_or_default();
-
-        if user.is_none() {
-            sqlx::query("INSERT INTO user (name) VALUES (?)")
-            .bind(&player.profile_name)
-            .execute(&conn)
-            .await.unwrap_or_default();
+        .bind(name)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(user) = user {
+            let claimed = match (claim, &user.claim) {
+                (Some(code), Some(hashed)) => auth::verify_passwd(code, hashed),
+                _ => false,
+            };
+            if !claimed {
+                return Ok(false);
+            }
+
+            sqlx::query("UPDATE user SET passwd = ?, claim = NULL where id = ?")
+            .bind(passwd)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query("INSERT INTO user (name, passwd) VALUES (?, ?)")
+            .bind(name)
+            .bind(passwd)
+            .execute(&mut *tx)
+            .await?;
         }
+
+        tx.commit().await?;
+        Ok(true)
     }
 
-    pub async fn on_race_finished(&mut self, results: &Vec<MetaRaceResult>) {
-        let conn = self.connect().await;
-        for result in results {
+    /// Store the hash of a new one-time claim code, false for an unknown user.
+    pub async fn on_user_reset(&self, name: &String, claim: &String) -> DbResult<bool> {
+        let result = sqlx::query("UPDATE user SET claim = ? WHERE name = ?")
+        .bind(claim)
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store the race and add the scored points to its players, rated races
+    /// also update the skill rating of the players not flagged. Everything runs
+    /// in one write transaction so races finishing together never overwrite
+    /// each other's updates.
+    pub async fn on_race_finished(&self, info: &RaceInfo, results: &[MetaRaceResult], flags: &[TelemetryFlag], rated: bool) -> DbResult<i64> {
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        let mut users: Vec<Option<User>> = vec![];
+        for result in results.iter() {
             let user: Option<User> = sqlx::query_as("SELECT * FROM user WHERE name = ?")
             .bind(&result.profile_name)
-            .fetch_optional(&conn)
-            .await.unwrap_or_default();
-
-            if let Some(user) = user {
-                let new_score = user.score + result.score;
-                let new_license = self.get_license(new_score);
-
-                sqlx::query("UPDATE user SET license = ?, score = ? where id = ?")
-                .bind(new_license)
-                .bind(new_score)
-                .bind(&user.id)
-                .execute(&conn)
-                .await.unwrap_or_default();
+            .fetch_optional(&mut *tx)
+            .await?;
+            users.push(user);
+        }
+
+        let ratings: Vec<Rating> = users.iter().map(|x| x.as_ref().map(User::get_rating).unwrap_or_default()).collect();
+        // flagged runs keep their rating, the other players are ranked among themselves.
+        let rated_index: Vec<usize> = (0..results.len())
+            .filter(|i| rated && !flags.iter().any(|x| x.name == results[*i].profile_name))
+            .collect();
+        let rated = rated_index.len() > 1;
+        let mut new_ratings = ratings.clone();
+        if rated {
+            let positions: Vec<u32> = rated_index.iter().enumerate().map(|(pos, i)| {
+                if results[*i].finishtime == 3600.0f32 { // retired players share the last place.
+                    rated_index.len() as u32
+                } else {
+                    pos as u32 + 1
+                }
+            }).collect();
+            let rated_ratings: Vec<Rating> = rated_index.iter().map(|i| ratings[*i]).collect();
+            for (i, rating) in rated_index.iter().zip(rating::rate_race(&rated_ratings, &positions)) {
+                new_ratings[*i] = rating;
+            }
+        }
+
+        let race_id = sqlx::query("INSERT INTO race (room, stage, stage_id, stage_type, stage_len, car_fixed, car, car_id, damage, weather, wetness, skytype, skytype_id, players) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(&info.name)
+        .bind(&info.stage)
+        .bind(info.stage_id)
+        .bind(&info.stage_type)
+        .bind(info.stage_len)
+        .bind(info.car_fixed)
+        .bind(&info.car)
+        .bind(info.car_id)
+        .bind(info.damage)
+        .bind(info.weather)
+        .bind(info.wetness)
+        .bind(&info.skytype)
+        .bind(info.skytype_id)
+        .bind(results.len() as u32)
+        .execute(&mut *tx)
+        .await?.last_insert_rowid();
+
+        for (i, result) in results.iter().enumerate() {
+            sqlx::query("INSERT INTO race_result (race_id, name, racecar, position, splittime1, splittime2, finishtime, difftime, score) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(race_id)
+            .bind(&result.profile_name)
+            .bind(&result.racecar)
+            .bind(i as u32 + 1)
+            .bind(result.splittime1)
+            .bind(result.splittime2)
+            .bind(result.finishtime)
+            .bind(result.difftime)
+            .bind(result.score)
+            .execute(&mut *tx)
+            .await?;
+
+            if let Some(user) = &users[i] {
+                let rating = &new_ratings[i];
+                sqlx::query("UPDATE user SET license = ?, score = score + ?, rating = ?, deviation = ?, volatility = ? where id = ?")
+                .bind(rating.get_license())
+                .bind(result.score)
+                .bind(rating.rating)
+                .bind(rating.deviation)
+                .bind(rating.volatility)
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+
+                if rated && rated_index.contains(&i) {
+                    sqlx::query("INSERT INTO rating_history (race_id, name, rating, deviation, volatility, delta) VALUES (?, ?, ?, ?, ?, ?)")
+                    .bind(race_id)
+                    .bind(&user.name)
+                    .bind(rating.rating)
+                    .bind(rating.deviation)
+                    .bind(rating.volatility)
+                    .bind(rating.rating - ratings[i].rating)
+                    .execute(&mut *tx)
+                    .await?;
+                }
             }
         }
+
+        for flag in flags {
+            sqlx::query("INSERT INTO telemetry_flag (race_id, name, reason) VALUES (?, ?, ?)")
+            .bind(race_id)
+            .bind(&flag.name)
+            .bind(&flag.reason)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(race_id)
+    }
+
+    pub async fn query_user_history(&self, name: &String, page: u32, size: u32) -> DbResult<RaceHistory> {
+        let total: u32 = sqlx::query_scalar("SELECT COUNT(*) FROM race_result WHERE name = ?")
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let records: Vec<RaceRecordRow> = sqlx::query_as("SELECT race.id AS race_id, race.room, race.stage, race_result.racecar AS car, race.skytype, race.players, race_result.position, race_result.splittime1, race_result.splittime2, race_result.finishtime, race_result.difftime, race_result.score, race.finished_at FROM race_result JOIN race ON race_result.race_id = race.id WHERE race_result.name = ? ORDER BY race.id DESC LIMIT ? OFFSET ?")
+        .bind(name)
+        .bind(size)
+        .bind(page as i64 * size as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(RaceHistory { total, page, size, records: records.into_iter().map(RaceRecord::from).collect() })
+    }
+
+    pub async fn query_race_detail(&self, race_id: i64) -> DbResult<Option<RaceDetail>> {
+        let race: Option<Race> = sqlx::query_as("SELECT * FROM race WHERE id = ?")
+        .bind(race_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(race) = race else {
+            return Ok(None);
+        };
+
+        let results: Vec<RaceResultRow> = sqlx::query_as("SELECT * FROM race_result WHERE race_id = ? ORDER BY position")
+        .bind(race_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(RaceDetail {
+            race_id: race.id,
+            finished_at: race.finished_at.clone(),
+            info: RaceInfo::from(race),
+            results: results.into_iter().map(MetaRaceResult::from).collect(),
+        }))
+    }
+
+    pub async fn query_stage_records(&self, stage_id: u32, filter: &StageRecordQuery, group_cars: &[String], limit: u32) -> DbResult<StageRecords> {
+        let mut records = StageRecords { stage_id, ..Default::default() };
+
+        let mut fastest = stage_record_query("race_result.finishtime", stage_id, filter, group_cars);
+        fastest.push(" ORDER BY time LIMIT ").push_bind(limit);
+        let rows: Vec<StageRecordRow> = fastest.build_query_as().fetch_all(&self.pool).await?;
+        records.fastest = rows.into_iter().map(StageRecord::from).collect();
+
+        let mut split1 = stage_record_query("race_result.splittime1", stage_id, filter, group_cars);
+        split1.push(" AND race_result.splittime1 > 0 ORDER BY time LIMIT 1");
+        let row: Option<StageRecordRow> = split1.build_query_as().fetch_optional(&self.pool).await?;
+        records.split1 = row.map(StageRecord::from);
+
+        let mut split2 = stage_record_query("race_result.splittime2", stage_id, filter, group_cars);
+        split2.push(" AND race_result.splittime2 > 0 ORDER BY time LIMIT 1");
+        let row: Option<StageRecordRow> = split2.build_query_as().fetch_optional(&self.pool).await?;
+        records.split2 = row.map(StageRecord::from);
+
+        // sqlite returns the other columns from the row holding the MIN() value.
+        let mut bests = stage_record_query("MIN(race_result.finishtime)", stage_id, filter, group_cars);
+        bests.push(" GROUP BY race_result.name ORDER BY time LIMIT ").push_bind(limit);
+        let rows: Vec<StageRecordRow> = bests.build_query_as().fetch_all(&self.pool).await?;
+        records.personal_bests = rows.into_iter().map(StageRecord::from).collect();
+
+        Ok(records)
     }
-}
\ No newline at end of file
+
+    pub async fn query_flagged_runs(&self, page: u32, size: u32) -> DbResult<Vec<FlaggedRun>> {
+        let rows: Vec<FlaggedRunRow> = sqlx::query_as("SELECT telemetry_flag.race_id, race.room, race.stage, telemetry_flag.name, race_result.finishtime, telemetry_flag.reason, telemetry_flag.created_at FROM telemetry_flag JOIN race ON telemetry_flag.race_id = race.id JOIN race_result ON race_result.race_id = telemetry_flag.race_id AND race_result.name = telemetry_flag.name ORDER BY telemetry_flag.id DESC LIMIT ? OFFSET ?")
+        .bind(size)
+        .bind(page as i64 * size as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(FlaggedRun::from).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn open_temp(name: &str) -> RaceDB {
+        let dbfile = std::env::temp_dir().join(format!("rbndata-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&dbfile);
+        RaceDB::open(&dbfile).await.unwrap()
+    }
+
+    fn result(name: &str, finishtime: f32) -> MetaRaceResult {
+        MetaRaceResult { profile_name: name.to_string(), splittime1: finishtime / 3.0, splittime2: finishtime / 1.5, finishtime, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn flagged_runs_hold_no_record() {
+        let db = open_temp("records").await;
+        let info = RaceInfo { name: "test".to_string(), stage_id: 10, ..Default::default() };
+        let flags = [TelemetryFlag { name: "cheat".to_string(), reason: "too fast".to_string() }];
+        db.on_race_finished(&info, &[result("cheat", 60.0), result("fair", 180.0)], &flags, true).await.unwrap();
+        db.on_race_finished(&info, &[result("fair", 170.0), result("cheat", 190.0)], &[], true).await.unwrap();
+
+        let records = db.query_stage_records(10, &StageRecordQuery::default(), &[], 20).await.unwrap();
+        let fastest: Vec<(&str, f32)> = records.fastest.iter().map(|x| (x.name.as_str(), x.time)).collect();
+        assert_eq!(fastest, vec![("fair", 170.0), ("fair", 180.0), ("cheat", 190.0)]);
+        assert_eq!(records.split1.unwrap().name, "fair");
+
+        let bests: Vec<(&str, f32)> = records.personal_bests.iter().map(|x| (x.name.as_str(), x.time)).collect();
+        assert_eq!(bests, vec![("fair", 170.0), ("cheat", 190.0)]);
+    }
+}
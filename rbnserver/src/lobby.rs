@@ -1,11 +1,38 @@
+use chrono::{DateTime, Local};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use std::collections::HashMap;
-use crate::player::LobbyPlayer;
+use crate::auth::REFRESH_EXPIRE_DAYS;
+use crate::player::{LobbyPlayer, UserRole};
+
+#[derive(Clone)]
+pub struct LobbySession {
+    pub name: String,
+    pub role: UserRole,
+    pub token: Uuid,
+    expire: DateTime<Local>,
+}
+
+impl LobbySession {
+    pub fn new(name: &String, role: &UserRole, token: &Uuid) -> Self {
+        Self {
+            name: name.clone(),
+            role: role.clone(),
+            token: *token,
+            expire: Local::now() + chrono::Duration::days(REFRESH_EXPIRE_DAYS),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Local::now() > self.expire
+    }
+}
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct RaceLobby {
     players: HashMap<Uuid, LobbyPlayer>,
+    #[serde(skip)]
+    sessions: HashMap<Uuid, LobbySession>,
 }
 
 impl RaceLobby {
@@ -32,6 +59,10 @@ impl RaceLobby {
         return false;
     }
 
+    pub fn get_players_counts(&self) -> usize {
+        self.players.len()
+    }
+
     pub fn get_player(&mut self, token: Uuid) -> Option<&mut LobbyPlayer> {
         if let Some(player) = self.players.get_mut(&token) {
             return Some(player);
@@ -48,7 +79,29 @@ impl RaceLobby {
         None
     }
 
-    pub fn check_players(&mut self) {
-        self.players.retain(|_, v| v.is_alive());
+    pub fn push_session(&mut self, refresh: Uuid, session: LobbySession) {
+        self.sessions.insert(refresh, session);
+    }
+
+    pub fn pop_session(&mut self, refresh: &Uuid) -> Option<LobbySession> {
+        self.sessions.remove(refresh)
+    }
+
+    pub fn revoke_sessions(&mut self, token: &Uuid) {
+        self.sessions.retain(|_, v| &v.token != token);
+    }
+
+    /// Drop players without heartbeat, their tokens are returned to leave the races.
+    pub fn check_players(&mut self) -> Vec<Uuid> {
+        let mut expired = vec![];
+        self.players.retain(|k, v| {
+            let alive = v.is_alive();
+            if !alive {
+                expired.push(*k);
+            }
+            alive
+        });
+        self.sessions.retain(|_, v| !v.is_expired());
+        expired
     }
 }
\ No newline at end of file
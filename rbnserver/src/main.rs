@@ -1,36 +1,53 @@
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::{dev::Service, web, App, HttpRequest, HttpResponse, HttpServer};
 use chrono::Local;
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::AsyncReadExt;
 use tokio::signal;
+use tokio::time::{Duration, Instant};
 use clap::Parser;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use log::{info, trace};
+use tokio::sync::broadcast;
+use log::{error, info, trace, warn};
+use std::path::PathBuf;
 
+use crate::auth::AuthPlayer;
+use crate::config::ServerConfig;
+use crate::db::RaceDB;
 use crate::server::RacingServer;
-use rbnproto::httpapi::{RaceConfigUpdate, RaceCreate, RaceInfoUpdate, RaceQuery, UserHeart, UserLogin, UserLogout, UserQuery};
-use rbnproto::API_VERSION_STRING;
-use rbnproto::metaapi::{META_HEADER_LEN, RaceUpdate, RaceAccess, RaceJoin, RaceLeave, MetaHeader, DataFormat, MetaRaceData};
-
+use crate::writer::MetaWriter;
+use crate::series::live::LiveSubscription;
+use crate::series::scoring::is_valid_scoring;
+use rbnproto::httpapi::{RaceConfigUpdate, RaceCreate, RaceHistoryQuery, RaceInfoUpdate, RaceQuery, StageRecordQuery, UserHeart, UserLogin, UserLogout, UserQuery, UserRefresh, UserRegister, VersionQuery};
+use rbnproto::{is_version_compatible, API_VERSION_STRING};
+use rbnproto::metaapi::{decode_body, unix_millis, DataFormat, MetaCodecError, MetaDecoder, MetaHandshake, MetaHeader, MetaKeepAlive, MetaPing, MetaPong, MetaRaceData, MetaRevision, RaceAccess, RaceJoin, RaceLeave, RaceUpdate};
+
+mod auth;
+mod config;
 mod db;
 mod series;
 mod lobby;
+mod metrics;
 mod player;
+mod rating;
 mod server;
+mod writer;
 
 /// Set http and metadata ports.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Port of the http service port
-    #[arg(short, long, default_value_t = 23555)]
-    port: u16,
+    /// Port of the http service port [default: 23555]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Port of the meta data service port [default: 23556]
+    #[arg(short, long)]
+    data: Option<u16>,
 
-    /// Port of the meta data service port
-    #[arg(short, long, default_value_t = 23556)]
-    data: u16,
+    /// Server config file in toml format
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -38,24 +55,69 @@ async fn main() -> std::io::Result<()>{
     env_logger::init();
 
     let args = Args::parse();
-    let http_addr = "0.0.0.0:".to_string() + &args.port.to_string();
-    let meta_addr = "0.0.0.0:".to_string() + &args.data.to_string();
+    let mut cfg = ServerConfig::default();
+    if let Some(file) = &args.config {
+        match ServerConfig::load(file) {
+            Ok(loaded) => cfg = loaded,
+            Err(e) => {
+                error!("Failed to load config file {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let http_port = args.port.unwrap_or(cfg.http_port);
+    let meta_port = args.data.unwrap_or(cfg.meta_port);
+    let schedule_tick = cfg.tick.schedule;
+    config::init(cfg);
+
+    let http_addr = "0.0.0.0:".to_string() + &http_port.to_string();
+    let meta_addr = "0.0.0.0:".to_string() + &meta_port.to_string();
+
+    let db = match RaceDB::open(&config::get().paths.get_db_file()).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to open database {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let server = Arc::new(Mutex::new(RacingServer::default().init()));
+    let server = Arc::new(RacingServer::new(db).init());
     let data_clone = server.clone();
     let mng_clone = server.clone();
-    db::RaceDB::default().migrate().await;
 
     let http_server = HttpServer::new(move || {
         App::new()
         .app_data(web::Data::new(server.clone()))
+        .wrap_fn(|req, srv| {
+            let start = std::time::Instant::now();
+            let method = req.method().to_string();
+            let response = srv.call(req);
+            async move {
+                let response = response.await?;
+                let path = response.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                metrics::get().http_latency.with_label_values(&[&method, &path, response.status().as_str()])
+                    .observe(start.elapsed().as_secs_f64());
+                Ok(response)
+            }
+        })
+        .service(handle_http_metrics)
         .service(handle_http_api_version)
+        .service(handle_http_api_version_check)
+        .service(handle_http_user_register)
         .service(handle_http_user_login)
+        .service(handle_http_user_refresh)
         .service(handle_http_user_heartbeat)
         .service(handle_http_user_logout)
         .service(handle_http_user_fetch_score)
+        .service(handle_http_user_fetch_history)
+        .service(handle_http_user_fetch_rating)
         .service(handle_http_race_fetch_news)
+        .service(handle_http_race_fetch_result)
+        .service(handle_http_stage_fetch_records)
+        .service(handle_http_admin_fetch_flags)
+        .service(handle_http_admin_reset_user)
         .service(handle_http_race_fetch_list)
+        .service(handle_ws_race_live)
         .service(handle_http_race_get_info)
         .service(handle_http_race_update_info)
         .service(handle_http_race_get_state)
@@ -66,12 +128,15 @@ async fn main() -> std::io::Result<()>{
         .service(handle_http_race_set_start)
         .service(handle_http_race_create)
         .service(handle_http_race_join)
+        .service(handle_http_race_spectate)
         .service(handle_http_race_leave)
         .service(handle_http_race_destroy)
         .service(handle_http_image_get)
         .service(handle_http_file_download)
         .service(handle_web_index)
         .service(handle_web_rankboard)
+        .service(handle_web_live)
+        .service(handle_web_stage_records)
     })
     .bind(http_addr)?
     .run();
@@ -84,20 +149,16 @@ async fn main() -> std::io::Result<()>{
         }
     });
 
+    // rooms tick on their own tasks, only closed rooms and idle players are swept here.
     let mgr_task = tokio::spawn(async move {
         loop {
-            let mut server = mng_clone.lock().await;
-            server.recycle_invalid_races();
-            server.recycle_invalid_players();
-            for (_, race) in server.races.iter_mut() {
-                race.framed_schedule();
-            }
+            mng_clone.recycle_invalid_races().await;
+            mng_clone.recycle_invalid_players().await;
             if cfg!(debug_assertions) {
-                server.dynamic_reload_templates();
+                mng_clone.dynamic_reload_templates().await;
             }
 
-            drop(server);
-            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+            tokio::time::sleep(tokio::time::Duration::from_millis(schedule_tick)).await;
         }
     });
 
@@ -108,8 +169,8 @@ async fn main() -> std::io::Result<()>{
     });
 
     info!("Rust RBR Online Server Version: V{}", std::env!("CARGO_PKG_VERSION"));
-    info!("Http server listening on port {}", args.port);
-    info!("Data listener listening on port {}", args.data);
+    info!("Http server listening on port {}", http_port);
+    info!("Data listener listening on port {}", meta_port);
 
     let _ = tokio::join!(http_server, data_task, mgr_task, sig_task);
     Ok(())
@@ -120,36 +181,85 @@ async fn handle_http_api_version() -> HttpResponse {
     HttpResponse::Ok().body(API_VERSION_STRING)
 }
 
+#[actix_web::post("/api/version")]
+async fn handle_http_api_version_check(body: web::Json<VersionQuery>) -> HttpResponse {
+    let query = body.into_inner();
+    trace!("Received client version check: {:?}", query);
+
+    if is_version_compatible(&query.version) {
+        HttpResponse::Ok().body(API_VERSION_STRING)
+    } else {
+        HttpResponse::UpgradeRequired().body(version_notice(&query.version))
+    }
+}
+
+fn version_notice(version: &String) -> String {
+    format!("Version {} is out of date, please update to {}.", version, API_VERSION_STRING)
+}
+
+fn database_error(e: sqlx::Error) -> HttpResponse {
+    error!("Database error: {}", e);
+    HttpResponse::InternalServerError().body("Database error!")
+}
+
+#[actix_web::post("/api/user/register")]
+async fn handle_http_user_register(data: web::Data<Arc<RacingServer>>, body: web::Json<UserRegister>) -> HttpResponse {
+    let user = body.into_inner();
+    info!("Received user register: {}", user.name);
+
+    if !auth::is_register_valid(&user) {
+        return HttpResponse::BadRequest().body("Name required and password too short!");
+    }
+
+    if data.user_register(user).await {
+        HttpResponse::Ok().body("Register successful!")
+    } else {
+        HttpResponse::Conflict().body("Register failed!")
+    }
+}
+
 #[actix_web::post("/api/user/login")]
-async fn handle_http_user_login(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<UserLogin>) -> HttpResponse {
+async fn handle_http_user_login(data: web::Data<Arc<RacingServer>>, body: web::Json<UserLogin>) -> HttpResponse {
     let user = body.into_inner();
-    info!("Received user login: {:?}", user);
+    info!("Received user login: {}", user.name);
 
-    let mut server = data.lock().await;
-    if let Some(tokenstr) = server.user_login(user).await {
-        HttpResponse::Ok().body(tokenstr)
+    let name = user.name.clone();
+    if let Some(response) = data.user_login(user).await {
+        HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
+    } else if data.is_user_unknown(&name).await {
+        HttpResponse::NotFound().body("User not found!")
     } else {
         HttpResponse::Unauthorized().body("Login failed!")
     }
 }
 
+#[actix_web::post("/api/user/refresh")]
+async fn handle_http_user_refresh(data: web::Data<Arc<RacingServer>>, body: web::Json<UserRefresh>) -> HttpResponse {
+    let refresh = body.into_inner();
+    trace!("Received user refresh token");
+
+    if let Some(response) = data.user_refresh(refresh).await {
+        HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
+    } else {
+        HttpResponse::Unauthorized().body("Refresh failed!")
+    }
+}
+
 #[actix_web::post("/api/user/heartbeat")]
-async fn handle_http_user_heartbeat(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<UserHeart>) -> HttpResponse {
-    let user = body.into_inner();
+async fn handle_http_user_heartbeat(data: web::Data<Arc<RacingServer>>, player: AuthPlayer) -> HttpResponse {
+    let user = UserHeart { token: player.0.tokenstr };
     trace!("Received user heartbeat: {:?}", user);
-    let mut server = data.lock().await;
-    server.user_heartbeat(user);
+    data.user_heartbeat(user).await;
 
     HttpResponse::Ok().body(Local::now().to_string())
 }
 
 #[actix_web::post("/api/user/logout")]
-async fn handle_http_user_logout(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<UserLogout>) -> HttpResponse {
-    let user: UserLogout = body.into_inner();
+async fn handle_http_user_logout(data: web::Data<Arc<RacingServer>>, player: AuthPlayer) -> HttpResponse {
+    let user = UserLogout { token: player.0.tokenstr };
     info!("Received user logout: {:?}", user);
 
-    let mut server = data.lock().await;
-    if server.user_logout(user) {
+    if data.user_logout(user).await {
         HttpResponse::Ok().body("Logout successful!")
     } else {
         HttpResponse::NotAcceptable().body("Logout failed!")
@@ -157,45 +267,167 @@ async fn handle_http_user_logout(data: web::Data<Arc<Mutex<RacingServer>>>, body
 }
 
 #[actix_web::get("/api/user/score")]
-async fn handle_http_user_fetch_score(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<UserQuery>) -> HttpResponse {
-    let query = body.into_inner();
+async fn handle_http_user_fetch_score(data: web::Data<Arc<RacingServer>>, player: AuthPlayer) -> HttpResponse {
+    let query = UserQuery { token: player.0.tokenstr };
     trace!("Received user query user score: {:?}", query);
 
-    let mut server = data.lock().await;
-    if let Some(response) = server.get_user_score(&query.token).await {
-        HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
-    } else {
-        HttpResponse::NoContent().body("Get Race info failed!")
+    match data.get_user_score(&query.token).await {
+        Ok(Some(response)) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Ok(None) => HttpResponse::NoContent().body("Get Race info failed!"),
+        Err(e) => database_error(e),
+    }
+}
+
+#[actix_web::get("/api/user/{name}/history")]
+async fn handle_http_user_fetch_history(data: web::Data<Arc<RacingServer>>, path: web::Path<String>, query: web::Query<RaceHistoryQuery>) -> HttpResponse {
+    let name = path.into_inner();
+    let query = query.into_inner();
+    trace!("Received user query race history: {} {:?}", name, query);
+
+    match data.get_user_history(&name, &query).await {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(e) => database_error(e),
+    }
+}
+
+#[actix_web::get("/api/user/{name}/rating")]
+async fn handle_http_user_fetch_rating(data: web::Data<Arc<RacingServer>>, path: web::Path<String>) -> HttpResponse {
+    let name = path.into_inner();
+    trace!("Received user query rating: {}", name);
+
+    match data.get_user_rating(&name).await {
+        Ok(Some(response)) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => database_error(e),
+    }
+}
+
+#[actix_web::get("/api/race/{id}/result")]
+async fn handle_http_race_fetch_result(data: web::Data<Arc<RacingServer>>, path: web::Path<i64>) -> HttpResponse {
+    let race_id = path.into_inner();
+    trace!("Received user query race result: {}", race_id);
+
+    match data.get_race_detail(race_id).await {
+        Ok(Some(response)) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Ok(None) => HttpResponse::NoContent().body("Get Race result failed!"),
+        Err(e) => database_error(e),
+    }
+}
+
+#[actix_web::get("/api/admin/flags")]
+async fn handle_http_admin_fetch_flags(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, query: web::Query<RaceHistoryQuery>) -> HttpResponse {
+    let query = query.into_inner();
+    trace!("Received admin query flagged runs: {:?}", query);
+
+    if !player.0.is_admin() {
+        return HttpResponse::Forbidden().body("Admin permission required!");
+    }
+
+    match data.get_flagged_runs(&query).await {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(e) => database_error(e),
+    }
+}
+
+#[actix_web::post("/api/admin/user/{name}/reset")]
+async fn handle_http_admin_reset_user(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, path: web::Path<String>) -> HttpResponse {
+    let name = path.into_inner();
+    info!("Received admin reset user: {}", name);
+
+    if !player.0.is_admin() {
+        return HttpResponse::Forbidden().body("Admin permission required!");
+    }
+
+    match data.user_reset(&name).await {
+        Ok(Some(response)) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Ok(None) => HttpResponse::NotFound().body("User not found!"),
+        Err(e) => database_error(e),
+    }
+}
+
+#[actix_web::get("/api/stage/{id}/records")]
+async fn handle_http_stage_fetch_records(data: web::Data<Arc<RacingServer>>, path: web::Path<u32>, query: web::Query<StageRecordQuery>) -> HttpResponse {
+    let stage_id = path.into_inner();
+    let query = query.into_inner();
+    trace!("Received user query stage records: {} {:?}", stage_id, query);
+
+    match data.get_stage_records(stage_id, &query).await {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(e) => database_error(e),
     }
 }
 
 #[actix_web::get("/api/race/news")]
-async fn handle_http_race_fetch_news(data: web::Data<Arc<Mutex<RacingServer>>>) -> HttpResponse {
+async fn handle_http_race_fetch_news(data: web::Data<Arc<RacingServer>>) -> HttpResponse {
     trace!("Received user query race news");
 
-    let mut server = data.lock().await;
-    HttpResponse::Ok().body(serde_json::to_string(&server.get_race_news()).unwrap())
+    HttpResponse::Ok().body(serde_json::to_string(&data.get_race_news().await).unwrap())
 }
 
 #[actix_web::get("/api/race/list")]
-async fn handle_http_race_fetch_list(data: web::Data<Arc<Mutex<RacingServer>>>) -> HttpResponse {
+async fn handle_http_race_fetch_list(data: web::Data<Arc<RacingServer>>) -> HttpResponse {
     trace!("Received user query race list");
 
-    let mut server = data.lock().await;
-    if let Some(response) = server.get_race_list() {
+    if let Some(response) = data.get_race_list().await {
         HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
     } else {
         HttpResponse::NoContent().body("Get Race list failed!")
     }
 }
 
+#[actix_web::get("/api/race/live/{room}")]
+async fn handle_ws_race_live(data: web::Data<Arc<RacingServer>>, path: web::Path<String>, req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, actix_web::Error> {
+    let room = path.into_inner();
+    let subscription = data.subscribe_race_live(&room).await;
+    let Some(LiveSubscription { snapshot, mut rx }) = subscription else {
+        return Ok(HttpResponse::NotFound().body("Race room not found!"));
+    };
+
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    info!("Live timing of {} subscribed", room);
+    actix_web::rt::spawn(async move {
+        for text in snapshot {
+            if session.text(text).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                timing = rx.recv() => match timing {
+                    Ok(text) => if session.text(text).await.is_err() {
+                        return;
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => trace!("Live timing of {} skipped {} frames", room, skipped),
+                    Err(broadcast::error::RecvError::Closed) => break, // room recycled.
+                },
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        let _ = session.pong(&bytes).await;
+                    },
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {},
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[actix_web::get("/metrics")]
+async fn handle_http_metrics(data: web::Data<Arc<RacingServer>>) -> HttpResponse {
+    data.update_metrics().await;
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics::get().render())
+}
+
 #[actix_web::get("/api/race/info")]
-async fn handle_http_race_get_info(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceQuery>) -> HttpResponse {
+async fn handle_http_race_get_info(data: web::Data<Arc<RacingServer>>, body: web::Json<RaceQuery>) -> HttpResponse {
     let query = body.into_inner();
     trace!("Received user query race info: {:?}", query);
 
-    let mut server = data.lock().await;
-    if let Some(response) = server.get_race_info(&query.name) {
+    if let Some(response) = data.get_race_info(&query.name).await {
         HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
     } else {
         HttpResponse::NoContent().body("Get Race info failed!")
@@ -203,25 +435,24 @@ async fn handle_http_race_get_info(data: web::Data<Arc<Mutex<RacingServer>>>, bo
 }
 
 #[actix_web::put("/api/race/info")]
-async fn handle_http_race_update_info(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceInfoUpdate>) -> HttpResponse {
-    let update = body.into_inner();
+async fn handle_http_race_update_info(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceInfoUpdate>) -> HttpResponse {
+    let mut update = body.into_inner();
+    update.token = player.0.tokenstr;
     trace!("Received user update race info: {:?}", update);
 
-    let mut server = data.lock().await;
-    if server.update_race_info(update) {
+    if data.update_race_info(update).await {
         HttpResponse::Ok().body("Update race info successful!")
     } else {
-        HttpResponse::NoContent().body("Update Race info failed!")
+        HttpResponse::Forbidden().body("Update Race info failed!")
     }
 }
 
 #[actix_web::get("/api/player/config")]
-async fn handle_http_player_get_config(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<UserQuery>) -> HttpResponse {
-    let query = body.into_inner();
+async fn handle_http_player_get_config(data: web::Data<Arc<RacingServer>>, player: AuthPlayer) -> HttpResponse {
+    let query = UserQuery { token: player.0.tokenstr };
     trace!("Received user query race config: {:?}", query);
 
-    let mut server = data.lock().await;
-    if let Some(response) = server.get_player_race_config(&query) {
+    if let Some(response) = data.get_player_race_config(&query).await {
         HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
     } else {
         HttpResponse::NoContent().body("Get Race config failed!")
@@ -229,12 +460,12 @@ async fn handle_http_player_get_config(data: web::Data<Arc<Mutex<RacingServer>>>
 }
 
 #[actix_web::put("/api/player/config")]
-async fn handle_http_player_update_config(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceConfigUpdate>) -> HttpResponse {
-    let update = body.into_inner();
+async fn handle_http_player_update_config(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceConfigUpdate>) -> HttpResponse {
+    let mut update = body.into_inner();
+    update.token = player.0.tokenstr;
     trace!("Received user update race config: {:?}", update);
 
-    let mut server = data.lock().await;
-    if server.update_player_race_config(update) {
+    if data.update_player_race_config(update).await {
         HttpResponse::Ok().body("Update race config successful!")
     } else {
         HttpResponse::NoContent().body("Update Race config failed!")
@@ -242,12 +473,11 @@ async fn handle_http_player_update_config(data: web::Data<Arc<Mutex<RacingServer
 }
 
 #[actix_web::get("/api/race/state")]
-async fn handle_http_race_get_state(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceQuery>) -> HttpResponse {
+async fn handle_http_race_get_state(data: web::Data<Arc<RacingServer>>, body: web::Json<RaceQuery>) -> HttpResponse {
     let query = body.into_inner();
     trace!("Received user query race users state: {:?}", query);
 
-    let mut server = data.lock().await;
-    if let Some(response) = server.get_race_userstate(&query.name) {
+    if let Some(response) = data.get_race_userstate(&query.name).await {
         HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
     } else {
         HttpResponse::NoContent().body("Get Race user state failed!")
@@ -255,12 +485,12 @@ async fn handle_http_race_get_state(data: web::Data<Arc<Mutex<RacingServer>>>, b
 }
 
 #[actix_web::put("/api/race/state")]
-async fn handle_http_race_update_state(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceUpdate>) -> HttpResponse {
-    let info: RaceUpdate = body.into_inner();
+async fn handle_http_race_update_state(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceUpdate>) -> HttpResponse {
+    let mut info: RaceUpdate = body.into_inner();
+    info.token = player.0.tokenstr;
     info!("Received user update race state: {:?}", info);
 
-    let mut server = data.lock().await;
-    if server.update_player_state(&info) {
+    if data.update_player_state(&info).await {
         HttpResponse::Ok().body("Update race player state successful!")
     } else {
         HttpResponse::NotAcceptable().body("Update race player state failed!")
@@ -268,12 +498,11 @@ async fn handle_http_race_update_state(data: web::Data<Arc<Mutex<RacingServer>>>
 }
 
 #[actix_web::get("/api/race/start")]
-async fn handle_http_race_get_start(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceQuery>) -> HttpResponse {
+async fn handle_http_race_get_start(data: web::Data<Arc<RacingServer>>, body: web::Json<RaceQuery>) -> HttpResponse {
     let query = body.into_inner();
     trace!("Received user query room race start state: {:?}", query);
 
-    let mut server = data.lock().await;
-    if let Some(response) = server.get_race_started(&query.name) {
+    if let Some(response) = data.get_race_started(&query.name).await {
         HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
     } else {
         HttpResponse::NoContent().body("Get Race started state failed!")
@@ -281,12 +510,12 @@ async fn handle_http_race_get_start(data: web::Data<Arc<Mutex<RacingServer>>>, b
 }
 
 #[actix_web::put("/api/race/start")]
-async fn handle_http_race_set_start(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceAccess>) -> HttpResponse {
-    let access = body.into_inner();
+async fn handle_http_race_set_start(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceAccess>) -> HttpResponse {
+    let mut access = body.into_inner();
+    access.token = player.0.tokenstr;
     trace!("Received user set room race start: {:?}", access);
 
-    let mut server = data.lock().await;
-    if server.set_race_started(&access) {
+    if data.set_race_started(&access).await {
         HttpResponse::Ok().body("Update Race started state successful!")
     } else {
         HttpResponse::NoContent().body("Update Race started state failed!")
@@ -294,12 +523,20 @@ async fn handle_http_race_set_start(data: web::Data<Arc<Mutex<RacingServer>>>, b
 }
 
 #[actix_web::post("/api/race/create")]
-async fn handle_http_race_create(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceCreate>) -> HttpResponse {
-    let info = body.into_inner();
+async fn handle_http_race_create(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceCreate>) -> HttpResponse {
+    let mut info = body.into_inner();
+    info.token = player.0.tokenstr;
     info!("Received user create race info: {:?}", info);
 
-    let mut server = data.lock().await;
-    if server.create_race(info) {
+    if !is_valid_scoring(&info.scoring) {
+        return HttpResponse::BadRequest().body("Invalid scoring table!");
+    }
+
+    if info.limit.is_some_and(|x| x == 0 || x > config::get().room_limit) {
+        return HttpResponse::BadRequest().body("Invalid player limit!");
+    }
+
+    if data.create_race(info).await {
         HttpResponse::Ok().body("Create race successful!")
     } else {
         HttpResponse::NotAcceptable().body("Create race Failed!")
@@ -307,25 +544,38 @@ async fn handle_http_race_create(data: web::Data<Arc<Mutex<RacingServer>>>, body
 }
 
 #[actix_web::post("/api/race/join")]
-async fn handle_http_race_join(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceJoin>) -> HttpResponse {
-    let info = body.into_inner();
+async fn handle_http_race_join(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceJoin>) -> HttpResponse {
+    let mut info = body.into_inner();
+    info.token = player.0.tokenstr;
     info!("Received user join race info: {:?}", info);
 
-    let mut server = data.lock().await;
-    if server.join_race(info) {
+    if data.join_race(info).await {
         HttpResponse::Ok().body("Join race successful!")
     } else {
         HttpResponse::NotFound().body("Join race failed!")
     }
 }
 
+#[actix_web::post("/api/race/spectate")]
+async fn handle_http_race_spectate(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceJoin>) -> HttpResponse {
+    let mut info = body.into_inner();
+    info.token = player.0.tokenstr;
+    info!("Received user spectate race info: {:?}", info);
+
+    if data.spectate_race(info).await {
+        HttpResponse::Ok().body("Spectate race successful!")
+    } else {
+        HttpResponse::NotFound().body("Spectate race failed!")
+    }
+}
+
 #[actix_web::post("/api/race/leave")]
-async fn handle_http_race_leave(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceLeave>) -> HttpResponse {
-    let info: RaceLeave = body.into_inner();
+async fn handle_http_race_leave(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceLeave>) -> HttpResponse {
+    let mut info: RaceLeave = body.into_inner();
+    info.token = player.0.tokenstr;
     info!("Received user leave race info: {:?}", info);
 
-    let mut server = data.lock().await;
-    if server.leave_race(info.room, info.token) {
+    if data.leave_race(info.room, info.token).await {
         HttpResponse::Ok().body("Leave race room successful!")
     } else {
         HttpResponse::NotAcceptable().body("Leave race room failed!")
@@ -333,33 +583,31 @@ async fn handle_http_race_leave(data: web::Data<Arc<Mutex<RacingServer>>>, body:
 }
 
 #[actix_web::post("/api/race/destroy")]
-async fn handle_http_race_destroy(data: web::Data<Arc<Mutex<RacingServer>>>, body: web::Json<RaceAccess>) -> HttpResponse {
-    let info: RaceAccess = body.into_inner();
+async fn handle_http_race_destroy(data: web::Data<Arc<RacingServer>>, player: AuthPlayer, body: web::Json<RaceAccess>) -> HttpResponse {
+    let mut info: RaceAccess = body.into_inner();
+    info.token = player.0.tokenstr;
     info!("Received user destroy race info: {:?}", info);
 
-    let mut server = data.lock().await;
-    if server.destroy_race(info.room, info.token) {
+    if data.destroy_race(info.room, info.token).await {
         HttpResponse::Ok().body("Destroy race room successful!")
     } else {
-        HttpResponse::NotAcceptable().body("Destroy race room failed!")
+        HttpResponse::Forbidden().body("Destroy race room failed!")
     }
 }
 
 #[actix_web::get("/api/image/{file}")]
-async fn handle_http_image_get(data: web::Data<Arc<Mutex<RacingServer>>>, path: web::Path<String>) -> HttpResponse {
+async fn handle_http_image_get(data: web::Data<Arc<RacingServer>>, path: web::Path<String>) -> HttpResponse {
     let image_file = path.into_inner();
-    let mut server = data.lock().await;
-    if let Some(image_data) = server.load_image(&image_file).await {
+    if let Some(image_data) = data.load_image(&image_file).await {
         return HttpResponse::Ok().content_type("image/png").body(image_data);
     }
     return HttpResponse::NoContent().body("No such file.");
 }
 
 #[actix_web::get("/api/download/{file}")]
-async fn handle_http_file_download(data: web::Data<Arc<Mutex<RacingServer>>>, path: web::Path<String>) -> HttpResponse {
+async fn handle_http_file_download(data: web::Data<Arc<RacingServer>>, path: web::Path<String>) -> HttpResponse {
     let download_file = path.into_inner();
-    let mut server = data.lock().await;
-    if let Some(file_data) = server.load_file(&download_file).await {
+    if let Some(file_data) = data.load_file(&download_file).await {
         return HttpResponse::Ok().content_type("application/zip")
         .append_header(("Content-Disposition", format!("attachment; filename={}", download_file)))
         .body(file_data);
@@ -367,85 +615,196 @@ async fn handle_http_file_download(data: web::Data<Arc<Mutex<RacingServer>>>, pa
     return HttpResponse::NoContent().body("No such file.");
 }
 
-async fn handle_data_stream(stream: TcpStream, data: Arc<Mutex<RacingServer>>) {
-    let mut recvbuf = vec![0u8; 1024];
-    let mut remain = Vec::<u8>::new();
-    let (mut reader, writer) = stream.into_split();
-    let writer_clone = Arc::new(Mutex::new(writer));
-    while let Ok(n) = reader.read(&mut recvbuf).await {
-        if n == 0 {
-            break;
-        }
+async fn handle_data_stream(stream: TcpStream, data: Arc<RacingServer>) {
+    let (reader, writer) = stream.into_split();
+    let writer_clone = MetaWriter::spawn(writer);
+    let mut access = None;
+    metrics::get().meta_connections.inc();
+    serve_data_stream(reader, data.clone(), writer_clone.clone(), &mut access).await;
+    metrics::get().meta_connections.dec();
+
+    // keep the racer's slot, the helper may resume it with a new connection.
+    if let Some(access) = access {
+        data.race_player_disconnect(&access, &writer_clone).await;
+    }
+}
 
-        // 处理接收的数据
-        // 这里只是简单地将接收到的数据打印出来
-        // trace!("Received data: {:?}", &recvbuf[..n]);
+async fn serve_data_stream(mut reader: OwnedReadHalf, data: Arc<RacingServer>, mut writer: MetaWriter, access: &mut Option<RaceAccess>) {
+    let mut recvbuf = vec![0u8; 1024];
+    let mut decoder = MetaDecoder::default();
+    let mut handshaked = false;
+    let mut keepalive = tokio::time::interval(Duration::from_millis(config::get().tick.keepalive));
+    let heartbeat = Duration::from_secs(config::get().timeout.heartbeat as u64);
+    let mut last_seen = Instant::now();
+    loop {
+        let n = tokio::select! {
+            read = reader.read(&mut recvbuf) => match read {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            },
+            _ = writer.closed() => break, // slow or broken client dropped by the writer.
+            _ = keepalive.tick() => {
+                // peers before keepalive frames may stay silent while in the lobby.
+                let echoes = writer.get_revision() >= MetaRevision::KeepAlive;
+                if (echoes || !handshaked) && last_seen.elapsed() > heartbeat {
+                    warn!("drop meta connection, silent for {:?}", heartbeat);
+                    break;
+                }
+                if echoes && handshaked {
+                    writer.send_body(DataFormat::FmtKeepAlive, &MetaKeepAlive { server_time: unix_millis() });
+                }
+                continue;
+            }
+        };
 
-        let buffer = [&remain[..], &recvbuf[..n]].concat();
-        let datalen = buffer.len();
-        let mut offset = 0 as usize;
+        last_seen = Instant::now();
 
-        while offset + META_HEADER_LEN <= datalen {
-            if datalen < META_HEADER_LEN {
-                break;
+        decoder.feed(&recvbuf[..n]);
+        loop {
+            let frame = decoder.next_frame();
+            match &frame {
+                Ok(Some((head, _))) => metrics::get().meta_frames.with_label_values(&[&format!("{:?}", head.format)]).inc(),
+                Err(_) => metrics::get().meta_decode_errors.inc(),
+                Ok(None) => {},
             }
-            let head: MetaHeader = bincode::deserialize(&buffer[offset..offset+META_HEADER_LEN]).unwrap();
-
-            if (offset + META_HEADER_LEN + head.length as usize) > datalen {
-                break;
+            match frame {
+                Ok(Some((head, pack_data))) if !handshaked => {
+                    match meta_handshake_handle(head, &pack_data, writer.clone()).await {
+                        Ok(Some(revision)) => {
+                            writer.set_revision(revision);
+                            handshaked = true;
+                        }
+                        Ok(None) => return,
+                        Err(e) => {
+                            metrics::get().meta_decode_errors.inc();
+                            warn!("drop meta connection on bad handshake: {}", e);
+                            return;
+                        }
+                    }
+                }
+                Ok(Some((head, pack_data))) => {
+                    if let Err(e) = meta_message_handle(head, &pack_data, data.clone(), writer.clone(), access).await {
+                        metrics::get().meta_decode_errors.inc();
+                        warn!("drop meta connection on bad packet: {}", e);
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("drop meta connection on bad frame: {}", e);
+                    return;
+                }
             }
-            let pack_data = &buffer[offset+META_HEADER_LEN..offset+META_HEADER_LEN+head.length as usize];
-
-            meta_message_handle(head.clone(), pack_data, data.clone(), writer_clone.clone()).await;
-            offset += META_HEADER_LEN + head.length as usize;
         }
-        remain = (&buffer[offset..]).to_vec();
     }
 }
 
-async fn meta_message_handle(head: MetaHeader, pack_data: &[u8], data: Arc<Mutex<RacingServer>>, writer: Arc<Mutex<OwnedWriteHalf>>) {
-    let mut server = data.lock().await;
+/// Returns the protocol revision of an accepted client.
+async fn meta_handshake_handle(head: MetaHeader, pack_data: &[u8], writer: MetaWriter) -> Result<Option<MetaRevision>, MetaCodecError> {
+    if !matches!(head.format, DataFormat::FmtHandshake) {
+        warn!("drop meta connection without handshake: {:?}", head.format);
+        return Ok(None);
+    }
+
+    let client: MetaHandshake = decode_body(pack_data)?;
+    let mut reply = MetaHandshake {version: API_VERSION_STRING.to_string(), accepted: true, notice: String::new()};
+    if !is_version_compatible(&client.version) {
+        info!("reject meta client with version: {}", client.version);
+        reply.accepted = false;
+        reply.notice = version_notice(&client.version);
+    }
+
+    writer.send_body(DataFormat::FmtHandshake, &reply);
+    Ok(reply.accepted.then(|| MetaRevision::of(&client.version)))
+}
+
+async fn meta_message_handle(head: MetaHeader, pack_data: &[u8], data: Arc<RacingServer>, writer: MetaWriter, access: &mut Option<RaceAccess>) -> Result<(), MetaCodecError> {
     match head.format {
+        DataFormat::FmtPing => { // answered right away, the client measures its clock offset.
+            let ping: MetaPing = decode_body(pack_data)?;
+            writer.send_body(DataFormat::FmtPong, &MetaPong { client_time: ping.client_time, server_time: unix_millis() });
+        }
+        DataFormat::FmtKeepAlive => { // echo of our keepalive frame.
+            let keepalive: MetaKeepAlive = decode_body(pack_data)?;
+            let latency = (unix_millis() - keepalive.server_time).max(0);
+            metrics::get().meta_rtt.observe(latency as f64 / 1000.0);
+            if let Some(access) = access {
+                data.update_player_latency(access, latency as u32).await;
+            }
+        }
         DataFormat::FmtUserAccess => {
-            let user: RaceAccess = bincode::deserialize(pack_data).unwrap();
+            let user: RaceAccess = decode_body(pack_data)?;
             info!("recv racer access: {:?}", user);
-            server.race_player_access(&user, writer);
+            if data.race_player_access(&user, writer).await {
+                *access = Some(user);
+            }
         }
         DataFormat::FmtUpdateState => { // race update game state
-            let state: RaceUpdate = bincode::deserialize(pack_data).unwrap();
+            let state: RaceUpdate = decode_body(pack_data)?;
             info!("recv racer state update: {:?}", state);
-            server.update_player_state(&state);
+            data.update_player_state(&state).await;
         }
 
         DataFormat::FmtUploadData => { // user exchange racing data.
-            let racedata: MetaRaceData = bincode::deserialize(pack_data).unwrap();
-            server.update_player_race_data(racedata);
+            let racedata: MetaRaceData = decode_body(pack_data)?;
+            data.update_player_race_data(racedata).await;
         }
         _ => {}
     }
+    Ok(())
 }
 
 #[actix_web::get("/")]
-async fn handle_web_index(data: web::Data<Arc<Mutex<RacingServer>>>) -> HttpResponse {
-    let server = data.lock().await;
+async fn handle_web_index(data: web::Data<Arc<RacingServer>>) -> HttpResponse {
     let mut context = tera::Context::new();
     context.insert("last_release", "RBNHelper_latest.zip");
-    let rendered = server.tera.render("index.html", &context)
+    let rendered = data.tera.read().await.render("index.html", &context)
         .expect("failed to render template");
 
     HttpResponse::Ok().content_type("text/html").body(rendered)
 }
 
 #[actix_web::get("/rankboard")]
-async fn handle_web_rankboard(data: web::Data<Arc<Mutex<RacingServer>>>) -> HttpResponse {
-    let mut server = data.lock().await;
+async fn handle_web_rankboard(data: web::Data<Arc<RacingServer>>) -> HttpResponse {
 
     let mut context = tera::Context::new();
-    let players = server.get_all_user_score().await;
+    let players = match data.get_all_user_score().await {
+        Ok(players) => players,
+        Err(e) => return database_error(e),
+    };
     context.insert("players", &players);
 
-    let rendered = server.tera.render("rank.html", &context)
+    let rendered = data.tera.read().await.render("rank.html", &context)
         .expect("Failed to render template");
 
     HttpResponse::Ok().content_type("text/html").body(rendered)
-}
\ No newline at end of file
+}
+#[actix_web::get("/live")]
+async fn handle_web_live(data: web::Data<Arc<RacingServer>>) -> HttpResponse {
+
+    let mut context = tera::Context::new();
+    let rooms = data.get_race_overview().await;
+    context.insert("rooms", &rooms);
+
+    let rendered = data.tera.read().await.render("live.html", &context)
+        .expect("Failed to render template");
+
+    HttpResponse::Ok().content_type("text/html").body(rendered)
+}
+
+#[actix_web::get("/rankboard/stage/{id}")]
+async fn handle_web_stage_records(data: web::Data<Arc<RacingServer>>, path: web::Path<u32>, query: web::Query<StageRecordQuery>) -> HttpResponse {
+    let stage_id = path.into_inner();
+
+    let mut context = tera::Context::new();
+    let records = match data.get_stage_records(stage_id, &query.into_inner()).await {
+        Ok(records) => records,
+        Err(e) => return database_error(e),
+    };
+    context.insert("records", &records);
+
+    let rendered = data.tera.read().await.render("stage.html", &context)
+        .expect("Failed to render template");
+
+    HttpResponse::Ok().content_type("text/html").body(rendered)
+}
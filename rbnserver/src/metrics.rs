@@ -0,0 +1,82 @@
+use std::sync::OnceLock;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Prometheus metrics of the running server, scraped from `/metrics`.
+pub fn get() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub online_players: IntGauge,
+    /// Players in each series room, labeled by room name and room state.
+    pub series_players: IntGaugeVec,
+    /// Races by outcome: started, finished or aborted.
+    pub races: IntCounterVec,
+    pub race_dnf: IntCounter,
+    pub race_flagged: IntCounter,
+    pub meta_connections: IntGauge,
+    pub meta_frames: IntCounterVec,
+    pub meta_decode_errors: IntCounter,
+    pub meta_rtt: Histogram,
+    pub tick_duration: Histogram,
+    pub http_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::new_custom(Some("rbn".to_string()), None).unwrap(),
+            online_players: IntGauge::new("online_players", "Players logged into the lobby").unwrap(),
+            series_players: IntGaugeVec::new(Opts::new("series_players", "Players in each series room"), &["series", "state"]).unwrap(),
+            races: IntCounterVec::new(Opts::new("races_total", "Races by outcome"), &["outcome"]).unwrap(),
+            race_dnf: IntCounter::new("race_dnf_total", "Players retired from a race").unwrap(),
+            race_flagged: IntCounter::new("race_flagged_total", "Runs flagged by the telemetry checks").unwrap(),
+            meta_connections: IntGauge::new("meta_connections", "Open meta socket connections").unwrap(),
+            meta_frames: IntCounterVec::new(Opts::new("meta_frames_total", "Meta frames decoded"), &["format"]).unwrap(),
+            meta_decode_errors: IntCounter::new("meta_decode_errors_total", "Meta connections dropped on undecodable data").unwrap(),
+            meta_rtt: Histogram::with_opts(HistogramOpts::new("meta_rtt_seconds", "Meta connection round trip time")
+                .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.15, 0.2, 0.3, 0.5, 1.0])).unwrap(),
+            tick_duration: Histogram::with_opts(HistogramOpts::new("tick_duration_seconds", "Duration of one room schedule tick")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25])).unwrap(),
+            http_latency: HistogramVec::new(HistogramOpts::new("http_request_duration_seconds", "Http request latency"), &["method", "path", "status"]).unwrap(),
+        };
+
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.online_players.clone())).unwrap();
+        registry.register(Box::new(metrics.series_players.clone())).unwrap();
+        registry.register(Box::new(metrics.races.clone())).unwrap();
+        registry.register(Box::new(metrics.race_dnf.clone())).unwrap();
+        registry.register(Box::new(metrics.race_flagged.clone())).unwrap();
+        registry.register(Box::new(metrics.meta_connections.clone())).unwrap();
+        registry.register(Box::new(metrics.meta_frames.clone())).unwrap();
+        registry.register(Box::new(metrics.meta_decode_errors.clone())).unwrap();
+        registry.register(Box::new(metrics.meta_rtt.clone())).unwrap();
+        registry.register(Box::new(metrics.tick_duration.clone())).unwrap();
+        registry.register(Box::new(metrics.http_latency.clone())).unwrap();
+        for outcome in ["started", "finished", "aborted"] {
+            metrics.races.with_label_values(&[outcome]);
+        }
+        metrics
+    }
+
+    pub fn race_started(&self) {
+        self.races.with_label_values(&["started"]).inc();
+    }
+
+    pub fn race_finished(&self) {
+        self.races.with_label_values(&["finished"]).inc();
+    }
+
+    pub fn race_aborted(&self) {
+        self.races.with_label_values(&["aborted"]).inc();
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap_or_default();
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
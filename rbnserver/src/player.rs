@@ -1,18 +1,41 @@
-use std::sync::Arc;
 use chrono::{DateTime, Local};
-use rbnproto::httpapi::{RaceConfig, RaceState};
-use rbnproto::metaapi::{DataFormat, MetaHeader, MetaRaceData, MetaRaceProgress, MetaRaceResult, MetaRaceRidicule, MetaRaceState, RaceCmd};
+use log::{info, warn};
+use rbnproto::httpapi::{RaceConfig, RaceState, RaceUserState};
+use rbnproto::metaapi::{DataFormat, MetaRaceData, MetaRaceRidicule};
 use serde::{Serialize, Deserialize};
-use tokio::{sync::Mutex, net::tcp::OwnedWriteHalf, io::AsyncWriteExt};
 use uuid::Uuid;
+use crate::{config, metrics};
+use crate::auth::TOKEN_EXPIRE_HOURS;
+use crate::series::telemetry::TelemetryCheck;
+use crate::series::trace::DistanceTrace;
+use crate::writer::{MetaFrame, MetaWriter};
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum UserRole {
+    #[default]
+    Player,
+    Admin,
+}
+
+impl From<&str> for UserRole {
+    fn from(role: &str) -> Self {
+        match role {
+            "Admin" => UserRole::Admin,
+            _ => UserRole::Player,
+        }
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct LobbyPlayer {
     pub tokenstr: String,
     pub profile_name: String,
+    pub role: UserRole,
 
     #[serde(skip)]
     lastactive: DateTime<Local>,
+    #[serde(skip)]
+    expire: DateTime<Local>,
 }
 
 impl LobbyPlayer {
@@ -20,7 +43,9 @@ impl LobbyPlayer {
         Self { 
             tokenstr: token.clone(),
             profile_name: name.clone(),
-            lastactive: Local::now()
+            role: UserRole::default(),
+            lastactive: Local::now(),
+            expire: Local::now() + chrono::Duration::hours(TOKEN_EXPIRE_HOURS),
         }
     }
 
@@ -28,8 +53,25 @@ impl LobbyPlayer {
         self.lastactive = Local::now();
     }
 
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
+
+    pub fn renew(&mut self) {
+        self.set_alive();
+        self.expire = Local::now() + chrono::Duration::hours(TOKEN_EXPIRE_HOURS);
+    }
+
+    pub fn get_expire(&self) -> DateTime<Local> {
+        self.expire
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Local::now() > self.expire
+    }
+
     pub fn is_alive(&mut self) -> bool {
-        Local::now().signed_duration_since(self.lastactive) < chrono::Duration::seconds(60)
+        !self.is_expired() && Local::now().signed_duration_since(self.lastactive) < chrono::Duration::seconds(60)
     }
 }
 
@@ -39,7 +81,7 @@ pub struct RacePlayer {
     pub tokenstr: String,
     pub profile_name: String,
     #[serde(skip)]
-    pub writer: Option<Arc<Mutex<OwnedWriteHalf>>>,
+    pub writer: Option<MetaWriter>,
     pub state: RaceState,
     pub race_data: MetaRaceData,
     pub race_cfg: RaceConfig,
@@ -47,6 +89,17 @@ pub struct RacePlayer {
     pub lastridicule: DateTime<Local>,
     #[serde(skip)]
     pub last_race_data: MetaRaceData,
+    #[serde(skip)]
+    pub lastupdate: DateTime<Local>,
+    #[serde(skip)]
+    pub disconnected: Option<DateTime<Local>>,
+    #[serde(skip)]
+    pub telemetry: TelemetryCheck,
+    #[serde(skip)]
+    pub trace: DistanceTrace,
+    /// Round trip time of the meta connection in milliseconds.
+    #[serde(skip)]
+    pub latency: Option<u32>,
 }
 
 impl RacePlayer {
@@ -61,7 +114,75 @@ impl RacePlayer {
             race_cfg: RaceConfig::default(),
             lastridicule: Local::now(),
             last_race_data: MetaRaceData::default(),
+            lastupdate: Local::now(),
+            disconnected: None,
+            telemetry: TelemetryCheck::default(),
+            trace: DistanceTrace::default(),
+            latency: None,
+        }
+    }
+
+    /// Bind the meta connection, a player reconnecting within the grace period resumes the race.
+    pub fn attach(&mut self, writer: MetaWriter) {
+        if self.disconnected.take().is_some() {
+            info!("player {} resumed meta connection", self.profile_name);
+            self.lastupdate = Local::now();
         }
+        self.writer = Some(writer);
+    }
+
+    /// Only the connection currently bound is released, a newer one may already replace it.
+    pub fn detach(&mut self, writer: &MetaWriter) -> bool {
+        if let Some(current) = &self.writer {
+            if current.same_connection(writer) {
+                info!("player {} lost meta connection", self.profile_name);
+                self.writer = None;
+                self.latency = None;
+                self.disconnected = Some(Local::now());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether the player is out of the current race, a retirement sticks until the next race.
+    pub fn is_retired(&self) -> bool {
+        self.state == RaceState::RaceRetired
+    }
+
+    pub fn update_state(&mut self, state: RaceState) {
+        if self.is_retired() {
+            return;
+        }
+
+        if state == RaceState::RaceRetired {
+            self.retire();
+        } else {
+            self.state = state;
+        }
+    }
+
+    pub fn update_race_data(&mut self, data: MetaRaceData) {
+        if self.is_retired() {
+            return;
+        }
+
+        if let Some(reason) = self.telemetry.check(&data, Local::now(), &config::get().telemetry) {
+            warn!("flag race data of player {}: {}", self.profile_name, reason);
+            metrics::get().race_flagged.inc();
+        }
+        self.trace.record(data.progress, data.racetime);
+        self.race_data = data;
+        self.lastupdate = Local::now();
+    }
+
+    pub fn get_user_state(&self) -> RaceUserState {
+        RaceUserState { name: self.profile_name.clone(), state: self.state.clone(), latency: self.latency }
+    }
+
+    pub fn retire(&mut self) {
+        self.state = RaceState::RaceRetired;
+        self.race_data.finishtime = 3600.0f32;
     }
 
     pub fn sort_by_progress(&self, player: &RacePlayer) -> std::cmp::Ordering {
@@ -75,6 +196,10 @@ impl RacePlayer {
     }
 
     pub fn sort_by_time(&self, player: &RacePlayer) -> std::cmp::Ordering {
+        if self.is_retired() != player.is_retired() {
+            return self.is_retired().cmp(&player.is_retired());
+        }
+
         if self.race_data.finishtime > player.race_data.finishtime {
             return std::cmp::Ordering::Greater;
         } else if self.race_data.finishtime == player.race_data.finishtime {
@@ -84,51 +209,26 @@ impl RacePlayer {
         }
     }
 
-    pub async fn notify_user_cmd(&self, cmd: &RaceCmd) {
-        let body = bincode::serialize(cmd).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtRaceCommand}).unwrap();
+    pub fn notify_frame(&self, frame: &MetaFrame) {
         if let Some(writer) = &self.writer {
-            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+            writer.send(frame);
         }
     }
 
-    pub async fn notify_racestate(&self, result: &Vec::<MetaRaceState>) {
-        let body = bincode::serialize(result).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtSyncRaceState}).unwrap();
+    fn notify<T: Serialize>(&self, format: DataFormat, body: &T) {
         if let Some(writer) = &self.writer {
-            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
+            writer.send_body(format, body);
         }
     }
 
-    pub async fn notify_racedata(&self, result: &Vec::<MetaRaceProgress>) {
-        let body = bincode::serialize(result).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtSyncRaceData}).unwrap();
-        if let Some(writer) = &self.writer {
-            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
-        }
-    }
-
-    pub async fn notify_ridicule(&mut self, result: &MetaRaceRidicule) {
-        let body = bincode::serialize(result).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtSyncRaceRidicule}).unwrap();
-        if let Some(writer) = &self.writer {
-            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
-        }
-    }
-
-    pub async fn notify_result(&self, result: &Vec::<MetaRaceResult>) {
-        let body = bincode::serialize(result).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtSyncRaceResult}).unwrap();
-        if let Some(writer) = &self.writer {
-            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
-        }
+    pub fn notify_ridicule(&self, result: &MetaRaceRidicule) {
+        self.notify(DataFormat::FmtSyncRaceRidicule, result);
     }
+}
 
-    pub async fn notify_racenotice(&self, result: &String) {
-        let body = bincode::serialize(result).unwrap();
-        let head = bincode::serialize(&MetaHeader{length: body.len() as u16, format: DataFormat::FmtSyncRaceNotice}).unwrap();
-        if let Some(writer) = &self.writer {
-            writer.lock().await.write_all(&[&head[..], &body[..]].concat()).await.unwrap_or(());
-        }
+/// Encode the body once and queue the same frame to every player.
+pub fn broadcast<'a, T: Serialize>(players: impl IntoIterator<Item = &'a RacePlayer>, format: DataFormat, body: &T) {
+    if let Some(frame) = MetaFrame::encode(format, body) {
+        players.into_iter().for_each(|x| x.notify_frame(&frame));
     }
-}
\ No newline at end of file
+}
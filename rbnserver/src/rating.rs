@@ -0,0 +1,192 @@
+//! Glicko-2 skill rating, a multiplayer race is rated as one rating period in
+//! which every player met every other player once, the finishing order decides
+//! each pairwise outcome and retired players tie with each other.
+
+pub static RATING_DEFAULT: f64 = 1500.0;
+pub static DEVIATION_DEFAULT: f64 = 350.0;
+pub static VOLATILITY_DEFAULT: f64 = 0.06;
+
+static GLICKO_SCALE: f64 = 173.7178;
+static SYSTEM_TAU: f64 = 0.5;
+static CONVERGENCE: f64 = 0.000001;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: RATING_DEFAULT,
+            deviation: DEVIATION_DEFAULT,
+            volatility: VOLATILITY_DEFAULT,
+        }
+    }
+}
+
+impl Rating {
+    /// Lower bound of the rating with ~95% confidence, new players start low
+    /// and climb as their deviation shrinks.
+    pub fn conservative(&self) -> f64 {
+        self.rating - 2.0 * self.deviation
+    }
+
+    pub fn get_license(&self) -> String {
+        let rating = self.conservative();
+        if rating < 1200.0 {
+            "Rookie".to_string()
+        } else if rating < 1400.0 {
+            "Amateur".to_string()
+        } else if rating < 1600.0 {
+            "Master".to_string()
+        } else if rating < 1800.0 {
+            "Profession".to_string()
+        } else {
+            "Buglike".to_string()
+        }
+    }
+
+    fn mu(&self) -> f64 {
+        (self.rating - RATING_DEFAULT) / GLICKO_SCALE
+    }
+
+    fn phi(&self) -> f64 {
+        self.deviation / GLICKO_SCALE
+    }
+
+    /// Apply one rating period of games, each game is an opponent and the
+    /// score against him: 1.0 win, 0.5 draw, 0.0 loss.
+    pub fn update(&self, games: &[(Rating, f64)]) -> Rating {
+        let (mu, phi, sigma) = (self.mu(), self.phi(), self.volatility);
+        if games.is_empty() {
+            let phi = (phi * phi + sigma * sigma).sqrt();
+            return Rating { deviation: (phi * GLICKO_SCALE).min(DEVIATION_DEFAULT), ..*self };
+        }
+
+        let mut v_inv = 0.0;
+        let mut delta_sum = 0.0;
+        for (opponent, score) in games {
+            let g = g(opponent.phi());
+            let e = expect(mu, opponent.mu(), g);
+            v_inv += g * g * e * (1.0 - e);
+            delta_sum += g * (score - e);
+        }
+        let v = 1.0 / v_inv;
+        let delta = v * delta_sum;
+
+        let sigma = new_volatility(phi, sigma, v, delta);
+        let phi_star = (phi * phi + sigma * sigma).sqrt();
+        let phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let mu = mu + phi * phi * delta_sum;
+
+        Rating {
+            rating: mu * GLICKO_SCALE + RATING_DEFAULT,
+            deviation: phi * GLICKO_SCALE,
+            volatility: sigma,
+        }
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi * phi / (std::f64::consts::PI * std::f64::consts::PI)).sqrt()
+}
+
+fn expect(mu: f64, mu_j: f64, g: f64) -> f64 {
+    1.0 / (1.0 + (-g * (mu - mu_j)).exp())
+}
+
+/// Illinois algorithm from step 5 of the Glicko-2 paper.
+fn new_volatility(phi: f64, sigma: f64, v: f64, delta: f64) -> f64 {
+    let a = (sigma * sigma).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        let d = phi * phi + v + ex;
+        ex * (delta * delta - d) / (2.0 * d * d) - (x - a) / (SYSTEM_TAU * SYSTEM_TAU)
+    };
+
+    let mut big_a = a;
+    let mut big_b = if delta * delta > phi * phi + v {
+        (delta * delta - phi * phi - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * SYSTEM_TAU) < 0.0 {
+            k += 1.0;
+        }
+        a - k * SYSTEM_TAU
+    };
+
+    let mut f_a = f(big_a);
+    let mut f_b = f(big_b);
+    while (big_b - big_a).abs() > CONVERGENCE {
+        let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+        let f_c = f(big_c);
+        if f_c * f_b <= 0.0 {
+            big_a = big_b;
+            f_a = f_b;
+        } else {
+            f_a /= 2.0;
+        }
+        big_b = big_c;
+        f_b = f_c;
+    }
+
+    (big_a / 2.0).exp()
+}
+
+/// Rate a finished race, `positions` is the final place of each player where
+/// equal places are treated as a draw.
+pub fn rate_race(ratings: &[Rating], positions: &[u32]) -> Vec<Rating> {
+    ratings.iter().enumerate().map(|(i, rating)| {
+        let games: Vec<(Rating, f64)> = ratings.iter().enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(j, opponent)| {
+                let score = match positions[i].cmp(&positions[j]) {
+                    std::cmp::Ordering::Less => 1.0,
+                    std::cmp::Ordering::Equal => 0.5,
+                    std::cmp::Ordering::Greater => 0.0,
+                };
+                (*opponent, score)
+            })
+            .collect();
+        rating.update(&games)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating { rating, deviation, ..Default::default() }
+    }
+
+    #[test]
+    fn glickman_worked_example() {
+        let player = rating(1500.0, 200.0);
+        let games = [(rating(1400.0, 30.0), 1.0), (rating(1550.0, 100.0), 0.0), (rating(1700.0, 300.0), 0.0)];
+        let rated = player.update(&games);
+        assert!((rated.rating - 1464.06).abs() < 0.01, "{:?}", rated);
+        assert!((rated.deviation - 151.52).abs() < 0.01, "{:?}", rated);
+        assert!((rated.volatility - 0.05999).abs() < 0.00001, "{:?}", rated);
+    }
+
+    #[test]
+    fn race_is_rated_by_finishing_order() {
+        let rated = rate_race(&[Rating::default(); 4], &[2, 1, 4, 3]);
+        assert!(rated[1].rating > rated[0].rating);
+        assert!(rated[0].rating > RATING_DEFAULT);
+        assert!(rated[0].rating > rated[3].rating);
+        assert!(rated[3].rating > rated[2].rating);
+        assert!(rated[3].rating < RATING_DEFAULT);
+    }
+
+    #[test]
+    fn equal_places_draw() {
+        let rated = rate_race(&[Rating::default(); 3], &[1, 3, 3]);
+        assert_eq!(rated[1], rated[2]);
+        assert!(rated[0].rating > rated[1].rating);
+    }
+}
@@ -0,0 +1,71 @@
+use log::info;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use crate::{config, metrics};
+use super::Series;
+
+pub type BoxSeries = Box<dyn Series + Send + Sync>;
+
+type RoomJob = Box<dyn FnOnce(&mut BoxSeries) + Send>;
+
+/// A series runs as its own task owning all of its state, requests are queued
+/// to it so a busy room never stalls the lobby or any other room.
+#[derive(Clone)]
+pub struct RoomHandle {
+    tx: Sender<RoomJob>,
+}
+
+impl RoomHandle {
+    pub fn spawn(name: String, mut series: BoxSeries) -> Self {
+        let (tx, mut rx) = channel::<RoomJob>(256);
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_millis(config::get().tick.schedule));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    job = rx.recv() => match job {
+                        Some(job) => job(&mut series),
+                        None => break, // removed from the server.
+                    },
+                    _ = ticker.tick() => {
+                        let tick = std::time::Instant::now();
+                        series.framed_schedule();
+                        metrics::get().tick_duration.observe(tick.elapsed().as_secs_f64());
+                        if series.need_recycle() {
+                            break;
+                        }
+                    }
+                }
+            }
+            info!("race room {} closed", name);
+        });
+
+        Self { tx }
+    }
+
+    /// Run `job` inside the room and wait for its answer, `None` once the room is closed.
+    pub async fn call<R, F>(&self, job: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BoxSeries) -> R + Send + 'static,
+    {
+        let (reply, answer) = oneshot::channel();
+        self.tx.send(Box::new(move |series| {
+            let _ = reply.send(job(series));
+        })).await.ok()?;
+        answer.await.ok()
+    }
+
+    /// Queue `job` without waiting, dropped when the room is closed or lagging behind.
+    pub fn try_cast<F>(&self, job: F) -> bool
+    where
+        F: FnOnce(&mut BoxSeries) + Send + 'static,
+    {
+        self.tx.try_send(Box::new(job)).is_ok()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
@@ -1,24 +1,21 @@
-use rbnproto::httpapi::{RaceBrief, RaceConfig, RaceInfo, RaceState, RaceUserState};
+use chrono::{DateTime, Local};
+use rbnproto::httpapi::{RaceBrief, RaceConfig, RaceInfo, RaceScoring, RaceState, RaceUserState};
 use rbnproto::metaapi::{MetaRaceData, RaceJoin};
-use crate::lobby::RaceLobby;
+use crate::db::RaceDB;
 use crate::player::{LobbyPlayer, RacePlayer};
+use crate::writer::MetaWriter;
+use super::live::LiveSubscription;
 use super::room::RaceRoom;
 use super::Series;
 
+#[derive(Default)]
 pub struct Customize {
     room: RaceRoom,
 }
 
-impl Default for Customize {
-    fn default() -> Self {
-        let mut room = RaceRoom::default();
-        room.set_limit(8);
-        Self { room }
-    }
-}
-
 impl Series for Customize {
     fn join(&mut self, player: &LobbyPlayer){
+        self.room.pop_spectator(&player.tokenstr);
         self.room.push_player(RacePlayer::new(&player.tokenstr, &player.profile_name));
     }
 
@@ -26,12 +23,33 @@ impl Series for Customize {
         self.room.pop_player(token);
     }
 
-    fn access(&mut self, token: &String, writer: std::sync::Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>) -> bool {
+    fn spectate(&mut self, player: &LobbyPlayer) {
+        self.room.push_spectator(RacePlayer::new(&player.tokenstr, &player.profile_name));
+    }
+
+    fn access(&mut self, token: &String, writer: MetaWriter) -> bool {
         if let Some(player) = self.room.get_player(token) {
-            player.writer = Some(writer);
+            player.attach(writer);
             return true;
         }
 
+        if let Some(spectator) = self.room.get_spectator(token) {
+            spectator.attach(writer);
+            return true;
+        }
+
+        false
+    }
+
+    fn disconnect(&mut self, token: &String, writer: &MetaWriter) -> bool {
+        if let Some(player) = self.room.get_player(token) {
+            return player.detach(writer);
+        }
+
+        if let Some(spectator) = self.room.get_spectator(token) {
+            return spectator.detach(writer);
+        }
+
         false
     }
 
@@ -39,8 +57,8 @@ impl Series for Customize {
         self.room.is_empty()
     }
 
-    fn check_players(&mut self, lobby: &RaceLobby) {
-        self.room.players.retain(|x| lobby.is_player_exist(Some(&x.token), None));
+    fn is_protected(&mut self) -> bool {
+        false
     }
 
     fn is_joinable(&mut self, join: &RaceJoin) -> bool {
@@ -61,6 +79,20 @@ impl Series for Customize {
         true
     }
 
+    fn is_spectatable(&mut self, join: &RaceJoin) -> bool {
+        if self.room.get_player(&join.token).is_some() || self.room.is_spectator_exist(&join.token) {
+            return false;
+        }
+
+        if self.room.is_locked() {
+            if let Some(passwd) = &join.passwd {
+                return self.room.pass_match(passwd);
+            }
+            return false;
+        }
+        true
+    }
+
     fn is_started(&mut self) -> bool {
         self.room.is_racing_started()
     }
@@ -83,8 +115,12 @@ impl Series for Customize {
         self.room.info.clone()
     }
 
+    fn get_next_start(&mut self) -> Option<DateTime<Local>> {
+        None
+    }
+
     fn update_race_config(&mut self, info: RaceInfo) {
-        self.room.info = info;
+        self.room.set_info(info);
     }
 
     fn get_player_config(&mut self, token: &String) -> Option<RaceConfig> {
@@ -107,17 +143,12 @@ impl Series for Customize {
     }
 
     fn get_players_state(&mut self) -> Vec<RaceUserState> {
-        let mut states = vec![];
-        for player in &self.room.players {
-            let state = RaceUserState {name: player.profile_name.clone(), state: player.state.clone()};
-            states.push(state);
-        }
-        return states;
+        self.room.players.iter().map(|x| x.get_user_state()).collect()
     }
 
     fn update_player_state(&mut self, token: &String, state: RaceState) -> bool {
         if let Some(player) = self.room.get_player(token) {
-            player.state = state;
+            player.update_state(state);
             return true;
         }
         false
@@ -125,12 +156,24 @@ impl Series for Customize {
 
     fn update_player_data(&mut self, token: &String, data: MetaRaceData) -> bool {
         if let Some(player) = self.room.get_player(token) {
-            player.race_data = data;
+            player.update_race_data(data);
+            return true;
+        }
+        false
+    }
+
+    fn update_player_latency(&mut self, token: &String, latency: u32) -> bool {
+        if let Some(player) = self.room.get_player(token) {
+            player.latency = Some(latency);
             return true;
         }
         false
     }
 
+    fn subscribe_live(&mut self) -> LiveSubscription {
+        self.room.subscribe_live()
+    }
+
     fn framed_schedule(&mut self) {
         self.update_room_state();
         self.update_race_state();
@@ -138,10 +181,19 @@ impl Series for Customize {
 }
 
 impl Customize {
+    pub fn with_database(mut self, db: &RaceDB) -> Self {
+        self.room.set_database(db);
+        self
+    }
+
     pub fn set_limit(&mut self, limit: usize) {
         self.room.set_limit(limit);
     }
 
+    pub fn set_scoring(&mut self, scoring: &RaceScoring) {
+        self.room.set_scoring(scoring);
+    }
+
     pub fn lock_with_passwd(&mut self, passwd: &String) {
         self.room.set_pass(passwd.clone());
     }
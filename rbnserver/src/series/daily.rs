@@ -1,14 +1,18 @@
 use rbnproto::httpapi::{RaceBrief, RaceConfig, RaceInfo, RaceState, RaceUserState};
 use rbnproto::metaapi::{MetaRaceData, RaceJoin};
 use tokio::time::{Instant, Duration};
-use crate::lobby::RaceLobby;
+use crate::config::{self, SeriesConfig};
+use crate::db::RaceDB;
+use crate::metrics;
 use crate::player::{LobbyPlayer, RacePlayer};
+use crate::writer::MetaWriter;
 use log::{info, trace};
 use std::str::FromStr;
 use chrono::{DateTime, Local};
 use super::pithouse::RacePitHouse;
 use super::randomer::RaceRandomer;
-use super::room::{RaceRoom, RoomRaceState};
+use super::live::LiveSubscription;
+use super::room::RaceRoom;
 use super::Series;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
@@ -19,6 +23,7 @@ enum DailyMsg {
 
 pub struct Daily {
     room_name: String,
+    config: SeriesConfig,
     start_time: DateTime<Local>,
     tick_time: DateTime<Local>,
     pit: RacePitHouse,
@@ -32,6 +37,7 @@ impl Default for Daily {
         let (tx, rx) = channel::<DailyMsg>(8);
         Self {
             room_name: "Daily Challenge".to_string(),
+            config: SeriesConfig::default(),
             start_time: Local::now(),
             tick_time: Local::now(),
             pit: RacePitHouse::default(), 
@@ -44,6 +50,7 @@ impl Default for Daily {
 
 impl Series for Daily {
     fn join(&mut self, player: &LobbyPlayer) {
+        self.room.pop_spectator(&player.tokenstr);
         self.pit.push_player(RacePlayer::new(&player.tokenstr, &player.profile_name));
     }
 
@@ -52,12 +59,42 @@ impl Series for Daily {
         self.pit.pop_player(token);
     }
 
-    fn access(&mut self, token: &String, writer: std::sync::Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>) -> bool {
+    fn spectate(&mut self, player: &LobbyPlayer) {
+        self.room.push_spectator(RacePlayer::new(&player.tokenstr, &player.profile_name));
+    }
+
+    fn access(&mut self, token: &String, writer: MetaWriter) -> bool {
+        if let Some(player) = self.room.get_player(token) {
+            player.attach(writer);
+            return true;
+        }
+
         if let Some(player) = self.pit.get_player(token) {
-            player.writer = Some(writer);
+            player.attach(writer);
             return true;
         }
 
+        if let Some(spectator) = self.room.get_spectator(token) {
+            spectator.attach(writer);
+            return true;
+        }
+
+        false
+    }
+
+    fn disconnect(&mut self, token: &String, writer: &MetaWriter) -> bool {
+        if let Some(player) = self.room.get_player(token) {
+            return player.detach(writer);
+        }
+
+        if let Some(player) = self.pit.get_player(token) {
+            return player.detach(writer);
+        }
+
+        if let Some(spectator) = self.room.get_spectator(token) {
+            return spectator.detach(writer);
+        }
+
         false
     }
 
@@ -65,15 +102,19 @@ impl Series for Daily {
         false
     }
 
-    fn check_players(&mut self, lobby: &RaceLobby) {
-        self.room.players.retain(|x| lobby.is_player_exist(Some(&x.token), None));
-        self.pit.players.retain(|x| lobby.is_player_exist(Some(&x.token), None));
+    fn is_protected(&mut self) -> bool {
+        true
     }
 
     fn is_joinable(&mut self, _join: &RaceJoin) -> bool {
         true
     }
 
+    fn is_spectatable(&mut self, join: &RaceJoin) -> bool {
+        self.room.get_player(&join.token).is_none() && self.pit.get_player(&join.token).is_none()
+        && !self.room.is_spectator_exist(&join.token)
+    }
+
     fn is_started(&mut self) -> bool {
         self.room.is_racing_started()
     }
@@ -96,8 +137,12 @@ impl Series for Daily {
         self.room.info.clone()
     }
 
+    fn get_next_start(&mut self) -> Option<DateTime<Local>> {
+        Some(self.start_time)
+    }
+
     fn update_race_config(&mut self, info: RaceInfo) {
-        self.room.info = info;
+        self.room.set_info(info);
     }
 
     fn get_player_config(&mut self, token: &String) -> Option<RaceConfig> {
@@ -120,18 +165,13 @@ impl Series for Daily {
     }
 
     fn get_players_state(&mut self) -> Vec<RaceUserState> {
-        let mut states = vec![];
-        for player in &self.room.players {
-            let state = RaceUserState {name: player.profile_name.clone(), state: player.state.clone()};
-            states.push(state);
-        }
-        return states;
+        self.room.players.iter().map(|x| x.get_user_state()).collect()
     }
 
     fn update_player_state(&mut self, token: &String, state: RaceState) -> bool {
         if let Some(player) = self.room.get_player(token) {
             info!("update player state: {} -> {:?}", player.profile_name, state);
-            player.state = state;
+            player.update_state(state);
             return true;
         }
         false
@@ -143,12 +183,29 @@ impl Series for Daily {
         }
 
         if let Some(player) = self.room.get_player(token) {
-            player.race_data = data;
+            player.update_race_data(data);
         }
 
         true
     }
 
+    fn update_player_latency(&mut self, token: &String, latency: u32) -> bool {
+        if let Some(player) = self.room.get_player(token) {
+            player.latency = Some(latency);
+            return true;
+        }
+
+        if let Some(player) = self.pit.get_player(token) {
+            player.latency = Some(latency);
+            return true;
+        }
+        false
+    }
+
+    fn subscribe_live(&mut self) -> LiveSubscription {
+        self.room.subscribe_live()
+    }
+
     fn framed_schedule(&mut self) {
         self.async_msg_handle();
         self.update_room_state();
@@ -158,12 +215,19 @@ impl Series for Daily {
 }
 
 impl Daily {
-    pub fn named(name: &str) -> Self {
+    pub fn with_config(config: &SeriesConfig) -> Self {
         let mut series = Daily::default();
-        series.room_name = name.to_string();
+        series.room_name = config.name.clone();
+        series.room.set_scoring(&config.scoring);
+        series.config = config.clone();
         series
     }
 
+    pub fn with_database(mut self, db: &RaceDB) -> Self {
+        self.room.set_database(db);
+        self
+    }
+
     pub fn init(mut self) -> Self {
         self.generate_next_stage();
         self.generate_players();
@@ -182,28 +246,35 @@ impl Daily {
     }
 
     pub fn generate_next_stage(&mut self) {
+        let filter = &self.config.randomer;
         let mut randomer = RaceRandomer::build()
             .with_name(self.room_name.clone())
-            .with_owner("Lw_Ziye".to_string())
-            .with_exclude()
-            .fixed_damage(3);
+            .with_owner("Lw_Ziye".to_string());
 
-        if cfg!(debug_assertions) {
-            randomer = randomer.fixed_stage("Lyon - Gerland".to_string()).fixed_car("Hyundai i20 Coupe WRC 2021".to_string());
+        if filter.exclude {
+            randomer = randomer.with_exclude();
+        }
+        if let Some(stage) = &filter.stage {
+            randomer = randomer.fixed_stage(stage.clone());
+        }
+        if let Some(car) = &filter.car {
+            randomer = randomer.fixed_car(car.clone());
+        }
+        if filter.fixed_weather {
+            randomer = randomer.fixed_weather();
+        }
+        if let Some(damage) = self.config.damage {
+            randomer = randomer.fixed_damage(damage);
         }
 
-        self.room.info = randomer.random();
+        self.room.set_info(randomer.random());
         info!("next race: {:?}", &self.room.info);
     }
 
     pub fn trigger_next_stage(&mut self) {
         let tx = self.tx.clone();
+        let scheduler = cron::Schedule::from_str(&self.config.schedule).expect("Invalid series schedule");
         tokio::spawn(async move {
-            let mut scheduler = cron::Schedule::from_str("0 0/2 * * * *").unwrap();
-            if cfg!(debug_assertions) {
-                scheduler = cron::Schedule::from_str("0/30 * * * * *").unwrap();
-            }
-
             loop {
                 if let Some(next_time) = scheduler.upcoming(chrono::Local).take(1).next() {
                     let duration = next_time - Local::now();
@@ -238,19 +309,23 @@ impl Daily {
     fn update_room_state(&mut self) {
         self.room.update_room_state();
         if self.room.is_empty() { // no player exits, force to init state.
-            self.room.race_state = RoomRaceState::RoomRaceInit;
+            if self.room.is_racing_started() && !self.room.race.is_end() {
+                metrics::get().race_aborted();
+            }
+            self.room.race.reset(Local::now());
         }
     }
 
     fn update_race_state(&mut self) {
         self.room.update_race_state();
-        if self.room.race_state.eq(&RoomRaceState::RoomRaceEnd) {
+        self.room.take_dropped_players().into_iter().for_each(|x| self.pit.push_player(x));
+        if self.room.race.is_end() {
             self.restore_players();
         }
     }
 
     fn framed_notice(&mut self) {
-        if Local::now().signed_duration_since(self.tick_time) > chrono::Duration::milliseconds(500) {
+        if Local::now().signed_duration_since(self.tick_time) > chrono::Duration::milliseconds(config::get().tick.notice as i64) {
             self.tick_time = Local::now();
 
             if self.room.is_racing_started() {
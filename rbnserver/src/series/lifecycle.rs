@@ -0,0 +1,387 @@
+use chrono::{DateTime, Local};
+use rbnproto::httpapi::RaceState;
+use serde::{Serialize, Deserialize};
+use crate::config::TimeoutConfig;
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RoomRaceState {
+    #[default]
+    RoomRaceInit,
+    RoomRaceBegin,
+    RoomRacePrepare,
+    RoomRaceReady,
+    RoomRaceLoading,
+    RoomRaceLoaded,
+    RoomRaceStarting,
+    RoomRaceStarted,
+    RoomRaceRunning,
+    RoomRaceFinished,
+    RoomRaceExiting,
+    RoomRaceEnd,
+}
+
+/// What the lifecycle needs to know about a player in the race.
+#[derive(Clone, Debug)]
+pub struct PlayerStatus {
+    pub token: String,
+    pub state: RaceState,
+    pub lastupdate: DateTime<Local>,
+    pub disconnected: Option<DateTime<Local>>,
+}
+
+/// Outbound work for the room, the lifecycle itself never touches sockets or players.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LifecycleCmd {
+    ResetPlayers,
+    NotifyPrepare,
+    NotifyLoad,
+    NotifyStart,
+    NotifyUpload,
+    NotifyRaceData,
+    StoreResult,
+    DropPlayers(Vec<String>),
+    RetirePlayers(Vec<String>),
+    RestoreDropped,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct RaceLifecycle {
+    state: RoomRaceState,
+    state_tick: DateTime<Local>,
+    race_limit: i64,
+}
+
+fn is_finished(state: &RaceState) -> bool {
+    matches!(state, RaceState::RaceRetired | RaceState::RaceFinished | RaceState::RaceExitMenu)
+}
+
+impl RaceLifecycle {
+    pub fn get_state(&self) -> &RoomRaceState {
+        &self.state
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.state != RoomRaceState::RoomRaceInit
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.state == RoomRaceState::RoomRaceEnd
+    }
+
+    pub fn start(&mut self, now: DateTime<Local>) -> bool {
+        if self.is_started() {
+            return false;
+        }
+        self.set_state(RoomRaceState::RoomRaceBegin, now);
+        true
+    }
+
+    pub fn reset(&mut self, now: DateTime<Local>) {
+        self.set_state(RoomRaceState::RoomRaceInit, now);
+    }
+
+    /// Longest plausible race time in seconds, once exceeded every running player is retired.
+    pub fn set_race_limit(&mut self, seconds: i64) {
+        self.race_limit = seconds;
+    }
+
+    fn set_state(&mut self, state: RoomRaceState, now: DateTime<Local>) {
+        self.state = state;
+        self.state_tick = now;
+    }
+
+    fn is_timeout(&self, now: DateTime<Local>, seconds: i64) -> bool {
+        now.signed_duration_since(self.state_tick) > chrono::Duration::seconds(seconds)
+    }
+
+    /// Wait until every player reaches the expected state, players still behind
+    /// at the phase deadline are dropped and the race is given up if nobody is left.
+    fn wait_players(&mut self, now: DateTime<Local>, players: &[PlayerStatus], seconds: i64, expect: RaceState, next: RoomRaceState, cmds: &mut Vec<LifecycleCmd>) {
+        let mut waiting = players.to_vec();
+        if self.is_timeout(now, seconds) {
+            let (ready, dropped): (Vec<PlayerStatus>, Vec<PlayerStatus>) = waiting.into_iter().partition(|x| x.state == expect);
+            if !dropped.is_empty() {
+                cmds.push(LifecycleCmd::DropPlayers(dropped.into_iter().map(|x| x.token).collect()));
+            }
+            waiting = ready;
+        }
+
+        if waiting.is_empty() {
+            cmds.push(LifecycleCmd::RestoreDropped);
+            self.set_state(RoomRaceState::RoomRaceEnd, now);
+        } else if waiting.iter().all(|x| x.state == expect) {
+            self.set_state(next, now);
+        }
+    }
+
+    /// Advance one frame, with the current state of every player in the race.
+    pub fn update(&mut self, now: DateTime<Local>, players: &[PlayerStatus], timeout: &TimeoutConfig) -> Vec<LifecycleCmd> {
+        let mut cmds = vec![];
+        match self.state {
+            RoomRaceState::RoomRaceBegin => {
+                cmds.push(LifecycleCmd::ResetPlayers);
+                cmds.push(LifecycleCmd::NotifyPrepare);
+                self.set_state(RoomRaceState::RoomRacePrepare, now);
+            }
+            RoomRaceState::RoomRacePrepare => {
+                self.wait_players(now, players, timeout.prepare, RaceState::RaceReady, RoomRaceState::RoomRaceReady, &mut cmds);
+            }
+            RoomRaceState::RoomRaceReady => {
+                cmds.push(LifecycleCmd::NotifyLoad);
+                self.set_state(RoomRaceState::RoomRaceLoading, now);
+            }
+            RoomRaceState::RoomRaceLoading => {
+                self.wait_players(now, players, timeout.loading, RaceState::RaceLoaded, RoomRaceState::RoomRaceLoaded, &mut cmds);
+            }
+            RoomRaceState::RoomRaceLoaded => {
+                cmds.push(LifecycleCmd::NotifyStart);
+                self.set_state(RoomRaceState::RoomRaceStarting, now);
+            }
+            RoomRaceState::RoomRaceStarting => {
+                self.wait_players(now, players, timeout.starting, RaceState::RaceStarted, RoomRaceState::RoomRaceStarted, &mut cmds);
+            }
+            RoomRaceState::RoomRaceStarted => {
+                cmds.push(LifecycleCmd::NotifyUpload);
+                self.set_state(RoomRaceState::RoomRaceRunning, now);
+            }
+            RoomRaceState::RoomRaceRunning => {
+                let race_timeout = self.is_timeout(now, self.race_limit);
+                let stalled = chrono::Duration::seconds(timeout.stalled);
+                let reconnect = chrono::Duration::seconds(timeout.reconnect);
+                let retired: Vec<String> = players.iter()
+                    .filter(|x| !is_finished(&x.state))
+                    .filter(|x| race_timeout || match x.disconnected {
+                        Some(time) => now.signed_duration_since(time) > reconnect,
+                        None => now.signed_duration_since(x.lastupdate) > stalled,
+                    })
+                    .map(|x| x.token.clone())
+                    .collect();
+
+                if players.iter().all(|x| is_finished(&x.state) || retired.contains(&x.token)) {
+                    self.set_state(RoomRaceState::RoomRaceFinished, now);
+                }
+                if !retired.is_empty() {
+                    cmds.push(LifecycleCmd::RetirePlayers(retired));
+                }
+                cmds.push(LifecycleCmd::NotifyRaceData);
+            }
+            RoomRaceState::RoomRaceFinished => {
+                cmds.push(LifecycleCmd::StoreResult);
+                self.set_state(RoomRaceState::RoomRaceExiting, now);
+            }
+            RoomRaceState::RoomRaceExiting => {
+                if players.iter().all(|x| matches!(x.state, RaceState::RaceExitMenu | RaceState::RaceRetired)) || self.is_timeout(now, timeout.exiting) {
+                    self.set_state(RoomRaceState::RoomRaceEnd, now);
+                }
+            }
+            RoomRaceState::RoomRaceEnd => {
+                cmds.push(LifecycleCmd::RestoreDropped);
+                self.set_state(RoomRaceState::RoomRaceInit, now);
+            }
+            _ => {}
+        }
+
+        cmds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(token: &str, state: RaceState, now: DateTime<Local>) -> PlayerStatus {
+        PlayerStatus { token: token.to_string(), state, lastupdate: now, disconnected: None }
+    }
+
+    fn players(states: &[(&str, RaceState)], now: DateTime<Local>) -> Vec<PlayerStatus> {
+        states.iter().map(|(token, state)| player(token, state.clone(), now)).collect()
+    }
+
+    fn secs(now: DateTime<Local>, seconds: i64) -> DateTime<Local> {
+        now + chrono::Duration::seconds(seconds)
+    }
+
+    /// Run a fresh race until players are running.
+    fn run_to_running(race: &mut RaceLifecycle, tokens: &[&str], now: DateTime<Local>) {
+        let timeout = TimeoutConfig::default();
+        let with = |state: RaceState| players(&tokens.iter().map(|x| (*x, state.clone())).collect::<Vec<_>>(), now);
+        assert!(race.start(now));
+        race.update(now, &with(RaceState::RaceDefault), &timeout);
+        race.update(now, &with(RaceState::RaceReady), &timeout);
+        race.update(now, &with(RaceState::RaceReady), &timeout);
+        race.update(now, &with(RaceState::RaceLoaded), &timeout);
+        race.update(now, &with(RaceState::RaceLoaded), &timeout);
+        race.update(now, &with(RaceState::RaceStarted), &timeout);
+        race.set_race_limit(600);
+        race.update(now, &with(RaceState::RaceStarted), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceRunning);
+    }
+
+    #[test]
+    fn full_race_flow() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        assert!(!race.is_started());
+        assert!(race.start(now));
+        assert!(!race.start(now));
+
+        let steps = [
+            (RaceState::RaceDefault, vec![LifecycleCmd::ResetPlayers, LifecycleCmd::NotifyPrepare], RoomRaceState::RoomRacePrepare),
+            (RaceState::RaceInit, vec![], RoomRaceState::RoomRacePrepare),
+            (RaceState::RaceReady, vec![], RoomRaceState::RoomRaceReady),
+            (RaceState::RaceReady, vec![LifecycleCmd::NotifyLoad], RoomRaceState::RoomRaceLoading),
+            (RaceState::RaceLoaded, vec![], RoomRaceState::RoomRaceLoaded),
+            (RaceState::RaceLoaded, vec![LifecycleCmd::NotifyStart], RoomRaceState::RoomRaceStarting),
+            (RaceState::RaceStarted, vec![], RoomRaceState::RoomRaceStarted),
+            (RaceState::RaceStarted, vec![LifecycleCmd::NotifyUpload], RoomRaceState::RoomRaceRunning),
+            (RaceState::RaceRunning, vec![LifecycleCmd::NotifyRaceData], RoomRaceState::RoomRaceRunning),
+            (RaceState::RaceFinished, vec![LifecycleCmd::NotifyRaceData], RoomRaceState::RoomRaceFinished),
+            (RaceState::RaceFinished, vec![LifecycleCmd::StoreResult], RoomRaceState::RoomRaceExiting),
+            (RaceState::RaceExitMenu, vec![], RoomRaceState::RoomRaceEnd),
+            (RaceState::RaceExitMenu, vec![LifecycleCmd::RestoreDropped], RoomRaceState::RoomRaceInit),
+        ];
+        for (state, cmds, next) in steps {
+            let status = players(&[("a", state.clone()), ("b", state.clone())], now);
+            assert_eq!(race.update(now, &status, &timeout), cmds, "in state {:?}", state);
+            assert_eq!(race.get_state(), &next);
+        }
+        assert!(!race.is_started());
+    }
+
+    #[test]
+    fn player_leaving_mid_load_does_not_block() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        race.start(now);
+        race.update(now, &players(&[("a", RaceState::RaceReady), ("b", RaceState::RaceReady)], now), &timeout);
+        race.update(now, &players(&[("a", RaceState::RaceReady), ("b", RaceState::RaceReady)], now), &timeout);
+        race.update(now, &players(&[("a", RaceState::RaceReady), ("b", RaceState::RaceReady)], now), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceLoading);
+
+        race.update(now, &players(&[("a", RaceState::RaceLoaded), ("b", RaceState::RaceLoading)], now), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceLoading);
+
+        // b left the room while loading.
+        race.update(now, &players(&[("a", RaceState::RaceLoaded)], now), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceLoaded);
+    }
+
+    #[test]
+    fn everyone_leaving_gives_the_race_up() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        race.start(now);
+        race.update(now, &players(&[("a", RaceState::RaceDefault)], now), &timeout);
+        assert_eq!(race.update(now, &[], &timeout), vec![LifecycleCmd::RestoreDropped]);
+        assert!(race.is_end());
+    }
+
+    #[test]
+    fn prepare_timeout_drops_players_not_ready() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        race.start(now);
+        race.update(now, &players(&[("a", RaceState::RaceDefault), ("b", RaceState::RaceDefault)], now), &timeout);
+
+        let status = players(&[("a", RaceState::RaceReady), ("b", RaceState::RaceInit)], now);
+        assert!(race.update(secs(now, timeout.prepare - 1), &status, &timeout).is_empty());
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRacePrepare);
+
+        let cmds = race.update(secs(now, timeout.prepare + 1), &status, &timeout);
+        assert_eq!(cmds, vec![LifecycleCmd::DropPlayers(vec!["b".to_string()])]);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceReady);
+    }
+
+    #[test]
+    fn loading_timeout_without_players_left_ends_the_race() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        race.start(now);
+        race.update(now, &players(&[("a", RaceState::RaceReady)], now), &timeout);
+        race.update(now, &players(&[("a", RaceState::RaceReady)], now), &timeout);
+        race.update(now, &players(&[("a", RaceState::RaceReady)], now), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceLoading);
+
+        let cmds = race.update(secs(now, timeout.loading + 1), &players(&[("a", RaceState::RaceLoading)], now), &timeout);
+        assert_eq!(cmds, vec![LifecycleCmd::DropPlayers(vec!["a".to_string()]), LifecycleCmd::RestoreDropped]);
+        assert!(race.is_end());
+    }
+
+    #[test]
+    fn stalled_player_is_retired() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        run_to_running(&mut race, &["a", "b"], now);
+
+        let later = secs(now, timeout.stalled + 1);
+        let status = vec![player("a", RaceState::RaceFinished, later), player("b", RaceState::RaceRunning, now)];
+        let cmds = race.update(later, &status, &timeout);
+        assert_eq!(cmds, vec![LifecycleCmd::RetirePlayers(vec!["b".to_string()]), LifecycleCmd::NotifyRaceData]);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceFinished);
+    }
+
+    #[test]
+    fn disconnected_player_keeps_slot_for_grace_period() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        run_to_running(&mut race, &["a", "b"], now);
+
+        let mut b = player("b", RaceState::RaceRunning, now);
+        b.disconnected = Some(now);
+        let later = secs(now, timeout.stalled + 1);
+        let status = vec![player("a", RaceState::RaceRunning, later), b.clone()];
+        assert_eq!(race.update(later, &status, &timeout), vec![LifecycleCmd::NotifyRaceData]);
+
+        // resumed in time, the stalled timer restarts from the reconnect.
+        let resumed = secs(now, timeout.reconnect - 1);
+        let status = vec![player("a", RaceState::RaceRunning, resumed), player("b", RaceState::RaceRunning, resumed)];
+        assert_eq!(race.update(resumed, &status, &timeout), vec![LifecycleCmd::NotifyRaceData]);
+
+        let expired = secs(now, timeout.reconnect + 1);
+        let status = vec![player("a", RaceState::RaceRunning, expired), b];
+        let cmds = race.update(expired, &status, &timeout);
+        assert_eq!(cmds[0], LifecycleCmd::RetirePlayers(vec!["b".to_string()]));
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceRunning);
+    }
+
+    #[test]
+    fn race_over_limit_is_force_finished() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        run_to_running(&mut race, &["a", "b", "c"], now);
+
+        let later = secs(now, 601);
+        let status = vec![
+            player("a", RaceState::RaceFinished, later),
+            player("b", RaceState::RaceRunning, later),
+            player("c", RaceState::RaceRunning, later),
+        ];
+        let cmds = race.update(later, &status, &timeout);
+        assert_eq!(cmds[0], LifecycleCmd::RetirePlayers(vec!["b".to_string(), "c".to_string()]));
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceFinished);
+    }
+
+    #[test]
+    fn exiting_timeout_ends_the_race() {
+        let now = Local::now();
+        let timeout = TimeoutConfig::default();
+        let mut race = RaceLifecycle::default();
+        run_to_running(&mut race, &["a"], now);
+        race.update(now, &players(&[("a", RaceState::RaceFinished)], now), &timeout);
+        race.update(now, &players(&[("a", RaceState::RaceFinished)], now), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceExiting);
+
+        race.update(secs(now, timeout.exiting - 1), &players(&[("a", RaceState::RaceFinished)], now), &timeout);
+        assert_eq!(race.get_state(), &RoomRaceState::RoomRaceExiting);
+        race.update(secs(now, timeout.exiting + 1), &players(&[("a", RaceState::RaceFinished)], now), &timeout);
+        assert!(race.is_end());
+    }
+}
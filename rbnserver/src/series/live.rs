@@ -0,0 +1,80 @@
+use rbnproto::httpapi::{LiveTiming, RaceUserState, RoomState};
+use rbnproto::metaapi::{MetaRaceProgress, MetaRaceResult};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Live timing of one room as JSON for web pages, overlays and bots, the last
+/// room state, race states and results are kept for late subscribers.
+pub struct LiveFeed {
+    tx: Sender<String>,
+    room_state: RoomState,
+    race_state: Vec<RaceUserState>,
+    race_result: Vec<MetaRaceResult>,
+}
+
+pub struct LiveSubscription {
+    pub snapshot: Vec<String>,
+    pub rx: Receiver<String>,
+}
+
+impl Default for LiveFeed {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(64);
+        Self {
+            tx,
+            room_state: RoomState::default(),
+            race_state: vec![],
+            race_result: vec![],
+        }
+    }
+}
+
+fn encode(timing: &LiveTiming) -> Option<String> {
+    serde_json::to_string(timing).ok()
+}
+
+impl LiveFeed {
+    pub fn subscribe(&self) -> LiveSubscription {
+        let snapshot = [
+            LiveTiming::RoomState(self.room_state.clone()),
+            LiveTiming::RaceState(self.race_state.clone()),
+            LiveTiming::RaceResult(self.race_result.clone()),
+        ];
+        LiveSubscription {
+            snapshot: snapshot.iter().filter_map(encode).collect(),
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    fn publish(&self, timing: LiveTiming) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+
+        if let Some(text) = encode(&timing) {
+            let _ = self.tx.send(text);
+        }
+    }
+
+    pub fn update_room_state(&mut self, state: &RoomState) {
+        if &self.room_state != state {
+            self.room_state = state.clone();
+            self.publish(LiveTiming::RoomState(state.clone()));
+        }
+    }
+
+    pub fn update_race_state(&mut self, states: Vec<RaceUserState>) {
+        if self.race_state != states {
+            self.race_state = states.clone();
+            self.publish(LiveTiming::RaceState(states));
+        }
+    }
+
+    pub fn update_race_progress(&self, progress: &[MetaRaceProgress]) {
+        self.publish(LiveTiming::RaceProgress(progress.to_vec()));
+    }
+
+    pub fn update_race_result(&mut self, results: &[MetaRaceResult]) {
+        self.race_result = results.to_vec();
+        self.publish(LiveTiming::RaceResult(results.to_vec()));
+    }
+}
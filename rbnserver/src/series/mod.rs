@@ -1,45 +1,43 @@
-use std::sync::Arc;
-
+use chrono::{DateTime, Local};
 use rbnproto::{httpapi::{RaceBrief, RaceConfig, RaceInfo, RaceState, RaceUserState}, metaapi::{MetaRaceData, RaceJoin}};
-use serde::{Deserialize, Serialize};
-use tokio::{net::tcp::OwnedWriteHalf, sync::Mutex};
 
-use crate::{lobby::RaceLobby, player::LobbyPlayer};
+use crate::player::LobbyPlayer;
+use crate::writer::MetaWriter;
+use live::LiveSubscription;
 
+pub mod actor;
 pub mod customize;
 pub mod daily;
+pub mod lifecycle;
+pub mod live;
 pub mod room;
 pub mod pithouse;
 pub mod randomer;
-
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
-pub enum RoomRaceState {
-    #[default]
-    RoomRaceInit,
-    RoomRaceBegin,
-    RoomRaceReady,
-    RoomRaceLoading,
-    RoomRaceLoaded,
-    RoomRaceStarting,
-    RoomRaceStarted,
-    RoomRaceRunning,
-    RoomRaceFinished,
-    RoomRaceEnd,
-}
+pub mod scoring;
+pub mod telemetry;
+pub mod trace;
 
 pub trait Series {
     fn join(&mut self, user: &LobbyPlayer);
 
     fn leave(&mut self, token: &String);
 
-    fn access(&mut self, token: &String, writer: Arc<Mutex<OwnedWriteHalf>>) -> bool;
+    /// Watch the race without driving, spectators get race state, data and
+    /// results but never race commands or a score.
+    fn spectate(&mut self, user: &LobbyPlayer);
+
+    fn access(&mut self, token: &String, writer: MetaWriter) -> bool;
+
+    fn disconnect(&mut self, token: &String, writer: &MetaWriter) -> bool;
 
     fn is_joinable(&mut self, join: &RaceJoin) -> bool;
 
+    fn is_spectatable(&mut self, join: &RaceJoin) -> bool;
+
     fn need_recycle(&mut self) -> bool;
 
-    fn check_players(&mut self, lobby: &RaceLobby);
-    
+    fn is_protected(&mut self) -> bool;
+
     fn is_started(&mut self) -> bool;
 
     fn set_start(&mut self) -> bool;
@@ -48,6 +46,9 @@ pub trait Series {
 
     fn get_race_config(&mut self) -> RaceInfo;
 
+    /// Scheduled series know when their next race starts.
+    fn get_next_start(&mut self) -> Option<DateTime<Local>>;
+
     fn update_race_config(&mut self, info: RaceInfo);
 
     fn get_player_config(&mut self, token: &String) -> Option<RaceConfig>;
@@ -62,5 +63,9 @@ pub trait Series {
 
     fn update_player_data(&mut self, token: &String, data: MetaRaceData) -> bool;
 
+    fn update_player_latency(&mut self, token: &String, latency: u32) -> bool;
+
+    fn subscribe_live(&mut self) -> LiveSubscription;
+
     fn framed_schedule(&mut self);
 }
\ No newline at end of file
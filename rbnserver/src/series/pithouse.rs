@@ -1,5 +1,5 @@
-use rbnproto::metaapi::MetaRaceState;
-use crate::player::RacePlayer;
+use rbnproto::metaapi::{DataFormat, MetaRaceState};
+use crate::player::{broadcast, RacePlayer};
 
 
 #[derive(Default)]
@@ -75,12 +75,7 @@ impl RacePitHouse {
             return;
         }
 
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_racenotice(&notice).await;
-            }
-        });
+        broadcast(&self.players, DataFormat::FmtSyncRaceNotice, &notice);
     }
 
     pub fn notify_all_players_race_state(&mut self) {
@@ -91,11 +86,6 @@ impl RacePitHouse {
         for player in &self.players {
             states.push(MetaRaceState {name: player.profile_name.clone(), state: player.state.clone()});
         }
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_racestate(&states).await;
-            }
-        });
+        broadcast(&self.players, DataFormat::FmtSyncRaceState, &states);
     }
 }
\ No newline at end of file
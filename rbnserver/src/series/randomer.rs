@@ -1,7 +1,8 @@
-use std::{io::Read, path::PathBuf};
+use std::{io::Read, path::PathBuf, sync::OnceLock};
 use serde::{Deserialize, Serialize};
 use unicode_normalization::UnicodeNormalization;
 use rand::{thread_rng, Rng};
+use crate::config;
 use rbnproto::{httpapi::RaceInfo, rsfdata::{RBRCarData, RBRStageData, RBRStageWeather}};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,6 +11,13 @@ pub struct RBRStageExclude {
     pub name: String
 }
 
+static GAME_DATA: OnceLock<RaceRandomer> = OnceLock::new();
+
+/// Stages and cars of the rsfdata read once, for lookups outside of drawing a race.
+pub fn game_data() -> &'static RaceRandomer {
+    GAME_DATA.get_or_init(RaceRandomer::build)
+}
+
 pub struct RaceRandomer {
     rsfdata_path: PathBuf,
     pub stages: Vec<RBRStageData>,
@@ -28,7 +36,7 @@ pub struct RaceRandomer {
 impl Default for RaceRandomer {
     fn default() -> Self {
         Self { 
-            rsfdata_path: std::env::current_exe().unwrap().parent().unwrap().join("rsfdata"),
+            rsfdata_path: config::get().paths.get_data_dir(),
             stages: vec![],
             wetness: vec!["Dry", "Damp", "Wet"],
             weathers: vec!["Good", "Random", "Bad"],
@@ -119,6 +127,14 @@ impl RaceRandomer {
         self
     }
 
+    pub fn get_group_cars(&self, group: &String) -> Vec<String> {
+        self.cars.iter().filter(|x| &x.base_group_id == group).map(|x| x.name.clone()).collect()
+    }
+
+    pub fn get_stage_deftime(&self, stage_id: u32) -> Option<f32> {
+        self.stages.iter().find(|x| x.id == stage_id.to_string()).and_then(|x| x.deftime.parse::<f32>().ok())
+    }
+
     fn load_game_stages(&mut self) {
         let filepath = self.rsfdata_path.clone().join("stages_data.json");
         if let Ok(mut file) = std::fs::File::open(filepath) {
@@ -1,37 +1,36 @@
-use log::info;
-use rbnproto::httpapi::{RaceConfig, RaceInfo, RaceState, RoomState};
-use rbnproto::metaapi::{MetaRaceData, MetaRaceProgress, MetaRaceResult, MetaRaceRidicule, MetaRaceState, RaceCmd};
-use serde::{Serialize, Deserialize};
-use crate::db;
-use crate::player::RacePlayer;
+use log::{error, info, trace};
+use rbnproto::httpapi::{RaceConfig, RaceInfo, RaceScoring, RaceState, RoomState};
+use rbnproto::metaapi::{unix_millis, DataFormat, MetaRaceData, MetaRaceProgress, MetaRaceResult, LegacyRaceProgress, MetaRaceResultPage, MetaRaceRidicule, MetaRaceState, MetaRevision, RaceCmd, RACE_STATE_VIEW_SIZE, RACE_VIEW_SIZE};
+use crate::{config, metrics};
+use crate::db::RaceDB;
+use crate::player::{broadcast, RacePlayer};
+use crate::writer::{MetaFrame, MetaWriter};
+use super::lifecycle::{LifecycleCmd, PlayerStatus, RaceLifecycle, RoomRaceState};
+use super::live::{LiveFeed, LiveSubscription};
+use super::randomer::game_data;
+use super::scoring::{build_scoring, ScoringPolicy};
+use super::telemetry::{TelemetryCheck, TelemetryFlag};
+use super::trace::{select_view, time_gap, DistanceTrace};
 use chrono::{DateTime, Local};
-
-#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
-pub enum RoomRaceState {
-    #[default]
-    RoomRaceInit,
-    RoomRaceBegin,
-    RoomRacePrepare,
-    RoomRaceReady,
-    RoomRaceLoading,
-    RoomRaceLoaded,
-    RoomRaceStarting,
-    RoomRaceStarted,
-    RoomRaceRunning,
-    RoomRaceFinished,
-    RoomRaceExiting,
-    RoomRaceEnd,
-}
+use std::collections::BTreeMap;
 
 #[derive(Default)]
 pub struct RaceRoom {
     pub info: RaceInfo,
+    /// Default time of the stage in seconds, looked up when the stage is set.
+    deftime: Option<f32>,
     pub players: Vec<RacePlayer>,
+    /// Watch the race without driving, they only receive the race broadcasts.
+    pub spectators: Vec<RacePlayer>,
     pub room_state: RoomState,
-    pub race_state: RoomRaceState,
+    pub race: RaceLifecycle,
     limit: Option<usize>,
     passwd: Option<String>,
     rank_tick: DateTime<Local>,
+    dropped: Vec<RacePlayer>,
+    scoring: Box<dyn ScoringPolicy + Send + Sync>,
+    live: LiveFeed,
+    db: Option<RaceDB>,
 }
 
 impl RaceRoom {
@@ -43,12 +42,98 @@ impl RaceRoom {
         self.passwd = Some(pass);
     }
 
+    pub fn set_database(&mut self, db: &RaceDB) {
+        self.db = Some(db.clone());
+    }
+
+    pub fn set_scoring(&mut self, scoring: &RaceScoring) {
+        self.scoring = build_scoring(scoring);
+    }
+
+    pub fn set_info(&mut self, info: RaceInfo) {
+        self.deftime = game_data().get_stage_deftime(info.stage_id).filter(|x| *x > 0.0);
+        self.info = info;
+    }
+
     pub fn push_player(&mut self, player: RacePlayer) {
         self.players.push(player);
     }
 
     pub fn pop_player(&mut self, tokenstr: &String) {
         self.players.retain(|x| &x.tokenstr != tokenstr);
+        self.dropped.retain(|x| &x.tokenstr != tokenstr);
+        self.pop_spectator(tokenstr);
+    }
+
+    pub fn subscribe_live(&self) -> LiveSubscription {
+        self.live.subscribe()
+    }
+
+    pub fn push_spectator(&mut self, spectator: RacePlayer) {
+        self.spectators.push(spectator);
+    }
+
+    pub fn pop_spectator(&mut self, tokenstr: &String) {
+        self.spectators.retain(|x| &x.tokenstr != tokenstr);
+    }
+
+    pub fn get_spectator(&mut self, tokenstr: &String) -> Option<&mut RacePlayer> {
+        self.spectators.iter_mut().find(|x| &x.tokenstr == tokenstr)
+    }
+
+    pub fn is_spectator_exist(&mut self, tokenstr: &String) -> bool {
+        self.spectators.iter().any(|x| &x.tokenstr == tokenstr)
+    }
+
+    /// Everyone who receives the race broadcasts, racers first.
+    fn get_audience(&self) -> impl Iterator<Item = &RacePlayer> {
+        self.players.iter().chain(self.spectators.iter())
+    }
+
+    /// Send everyone of the audience at most limit entries of a list ordered like the
+    /// players, racers get the leader, themselves and the cars nearest on the road.
+    /// The same view is encoded once per protocol revision.
+    fn broadcast_view<T: Clone>(&self, entries: &[T], limit: usize, encode: impl Fn(&[T], MetaRevision) -> Option<MetaFrame>) {
+        if entries.len() <= limit {
+            let mut frames = BTreeMap::new();
+            for writer in self.get_audience().filter_map(|x| x.writer.as_ref()) {
+                if let Some(frame) = frames.entry(writer.get_revision()).or_insert_with(|| encode(entries, writer.get_revision())) {
+                    writer.send(frame);
+                }
+            }
+            return;
+        }
+
+        let progress: Vec<f32> = self.players.iter().map(|x| x.race_data.progress).collect();
+        for (index, player) in self.get_audience().enumerate() {
+            if let Some(writer) = &player.writer {
+                let me = (index < self.players.len()).then_some(index);
+                let view: Vec<T> = select_view(&progress, me, limit).into_iter().map(|i| entries[i].clone()).collect();
+                if let Some(frame) = encode(&view, writer.get_revision()) {
+                    writer.send(&frame);
+                }
+            }
+        }
+    }
+
+    /// Players dropped out of the current race for missing a deadline,
+    /// series with a pit take them over, the others get them back after the race.
+    pub fn take_dropped_players(&mut self) -> Vec<RacePlayer> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    fn restore_dropped_players(&mut self) {
+        let dropped = self.take_dropped_players();
+        dropped.into_iter().for_each(|x| self.players.push(x));
+    }
+
+    /// Longest plausible race time in seconds, a multiple of the stage default time.
+    fn get_race_limit(&self) -> i64 {
+        let factor = config::get().timeout.running_factor;
+        match self.deftime {
+            Some(deftime) => (deftime * factor) as i64,
+            None => (self.info.stage_len as f32 / 30.0 * 3.6 * factor) as i64, // default average speed 30km/h, 3.6km/h is 1m/s.
+        }
     }
 
     pub fn get_player(&mut self, tokenstr: &String) -> Option<&mut RacePlayer> {
@@ -60,24 +145,20 @@ impl RaceRoom {
         None
     }
 
-    pub fn get_player_nearby(&mut self, tokenstr: &String, dist: f32) -> Option<Vec<RacePlayer>> {
-        if let Some(center) = self.get_player(tokenstr) {
-            let center_pos = center.race_data.progress / center.race_data.stagelen * self.info.stage_len as f32;
-
-            let mut players = Vec::<RacePlayer>::new();
-            for player in &self.players {
-                if &player.tokenstr == tokenstr {
-                    continue;
-                }
+    /// Indexes of the other players within dist meters of the player.
+    pub fn get_player_nearby(&self, tokenstr: &String, dist: f32) -> Option<Vec<usize>> {
+        let center = self.players.iter().find(|x| &x.tokenstr == tokenstr)?;
+        let center_pos = center.race_data.progress / center.race_data.stagelen * self.info.stage_len as f32;
 
+        let players = self.players.iter().enumerate()
+            .filter(|(_, player)| &player.tokenstr != tokenstr)
+            .filter(|(_, player)| {
                 let player_pos = player.race_data.progress / player.race_data.stagelen * self.info.stage_len as f32;
-                if player_pos > center_pos - dist && player_pos < center_pos + dist {
-                    players.push(player.clone());
-                }
-            }
-            return Some(players);
-        }
-        None
+                player_pos > center_pos - dist && player_pos < center_pos + dist
+            })
+            .map(|(index, _)| index)
+            .collect();
+        Some(players)
     }
 
     pub fn is_player_exist(&mut self, name: &String) -> bool {
@@ -115,16 +196,12 @@ impl RaceRoom {
     }
 
     pub fn is_racing_started(&self) -> bool {
-        match self.race_state {
-            RoomRaceState::RoomRaceInit => false,
-            _ => true,
-        }
+        self.race.is_started()
     }
 
     pub fn set_racing_started(&mut self) -> bool {
-        if !self.is_racing_started() && !self.is_empty() {
-            self.race_state = RoomRaceState::RoomRaceBegin;
-            return true;
+        if !self.is_empty() {
+            return self.race.start(Local::now());
         }
         false
     }
@@ -137,51 +214,6 @@ impl RaceRoom {
         self.players.sort_by(|a, b| a.sort_by_time(b));
     }
 
-    pub fn is_all_players_ready(&mut self) -> bool {
-        self.players.iter().all(|x| {
-            match x.state {
-                RaceState::RaceReady => true,
-                _ => false,
-            }
-        })
-    }
-
-    pub fn is_all_players_loaded(&mut self) -> bool {
-        self.players.iter().all(|x| {
-            match x.state {
-                RaceState::RaceLoaded => true,
-                _ => false,
-            }
-        })
-    }
-
-    pub fn is_all_players_started(&mut self) -> bool {
-        self.players.iter().all(|x| {
-            match x.state {
-                RaceState::RaceStarted => true,
-                _ => false,
-            }
-        })
-    }
-
-    pub fn is_all_players_finish(&mut self) -> bool {
-        self.players.iter().all(|x| {
-            match x.state {
-                RaceState::RaceRetired | RaceState::RaceFinished | RaceState::RaceExitMenu => true,
-                _ => false,
-            }
-        })
-    }
-
-    pub fn is_all_players_exitmenu(&mut self) -> bool {
-        self.players.iter().all(|x| {
-            match x.state {
-                RaceState::RaceExitMenu => true,
-                _ => false,
-            }
-        })
-    }
-
     pub fn reset_all_players_state(&mut self) {
         self.players.iter_mut().for_each(|x| {
             x.state = RaceState::RaceDefault;
@@ -189,62 +221,44 @@ impl RaceRoom {
             x.last_race_data = MetaRaceData::default();
             x.race_data = MetaRaceData::default();
             x.lastridicule = Local::now();
+            x.lastupdate = Local::now();
+            x.telemetry = TelemetryCheck::default();
+            x.trace = DistanceTrace::default();
         });
     }
 
     pub fn notify_all_players_prepare(&mut self) {
-        let cmd = RaceCmd::RaceCmdPrepare(self.info.clone());
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_user_cmd(&cmd).await;
-            }
-        });
+        broadcast(&self.players, DataFormat::FmtRaceCommand, &RaceCmd::RaceCmdPrepare(self.info.clone()));
     }
 
     pub fn notify_all_players_load(&mut self) {
-        let cmd = RaceCmd::RaceCmdLoad;
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_user_cmd(&cmd).await;
-            }
-        });
+        broadcast(&self.players, DataFormat::FmtRaceCommand, &RaceCmd::RaceCmdLoad);
     }
 
+    /// Every player starts at the same server instant, clients convert it with their clock offset.
     pub fn notify_all_players_start(&mut self) {
-        let cmd = RaceCmd::RaceCmdStart;
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_user_cmd(&cmd).await;
-            }
-        });
+        let start = unix_millis() + config::get().tick.start as i64;
+        broadcast(&self.players, DataFormat::FmtRaceCommand, &RaceCmd::RaceCmdStart(start));
     }
 
     pub fn notify_all_players_upload(&mut self) {
-        let cmd = RaceCmd::RaceCmdUpload;
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_user_cmd(&cmd).await;
-            }
-        });
+        broadcast(&self.players, DataFormat::FmtRaceCommand, &RaceCmd::RaceCmdUpload);
     }
 
     pub fn get_race_progress(&mut self) -> Vec::<MetaRaceProgress> {
         let mut results = Vec::<MetaRaceProgress>::new();
-        let leader = self.players.first().unwrap().clone();
-        for player in &self.players {
+        let at = |player: &RacePlayer| (player.race_data.progress, player.race_data.racetime);
+        let leader = &self.players[0];
+        for (index, player) in self.players.iter().enumerate() {
             let mut result = MetaRaceProgress::default();
             result.profile_name = player.profile_name.clone();
             result.progress = player.race_data.progress;
-            let difflength = (leader.race_data.progress - player.race_data.progress) / player.race_data.stagelen * self.info.stage_len as f32;
-            if player.race_data.speed != 0f32 {
-                result.difffirst = difflength / player.race_data.speed * 3.6;
+            result.difffirst = time_gap(at(player), &leader.trace);
+            if let Some(ahead) = index.checked_sub(1).map(|i| &self.players[i]) {
+                result.diffahead = time_gap(at(player), &ahead.trace);
             }
-            else {
-                result.difffirst = difflength / 10.0 * 3.6; // default 10km/h as 3.6m/s.
+            if let Some(behind) = self.players.get(index + 1) {
+                result.diffbehind = time_gap(at(behind), &player.trace);
             }
             result.carlook = player.race_data.carlook.clone();
             result.carpos = player.race_data.carpos.clone();
@@ -256,7 +270,7 @@ impl RaceRoom {
     pub fn get_race_result(&mut self) -> Vec::<MetaRaceResult> {
         let mut results = Vec::<MetaRaceResult>::new();
         let leader = self.players.first().unwrap().clone();
-        for (i, player) in self.players.iter().enumerate() {
+        for player in &self.players {
             let mut result = MetaRaceResult::default();
             result.profile_name = player.profile_name.clone();
             result.racecar = player.race_cfg.car.clone();
@@ -264,11 +278,6 @@ impl RaceRoom {
             result.splittime2 = player.race_data.splittime2;
             result.finishtime = player.race_data.finishtime;
             result.difftime = player.race_data.finishtime - leader.race_data.finishtime;
-            if result.finishtime == 3600.0f32 { // if not complete race, default reduce 2 score.
-                result.score = -5i32;
-            } else {
-                result.score = (self.players.len() - i) as i32 * 3;
-            }
             results.push(result);
         }
         results
@@ -282,12 +291,7 @@ impl RaceRoom {
         for player in &self.players {
             states.push(MetaRaceState {name: player.profile_name.clone(), state: player.state.clone()});
         }
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_racestate(&states).await;
-            }
-        });
+        self.broadcast_view(&states, RACE_STATE_VIEW_SIZE, |view, _| MetaFrame::encode(DataFormat::FmtSyncRaceState, &view));
     }
 
     pub fn notify_all_players_race_data(&mut self) {
@@ -297,10 +301,12 @@ impl RaceRoom {
 
         self.sort_players_by_progress();
         let results = self.get_race_progress();
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_racedata(&results).await;
+        self.live.update_race_progress(&results);
+        self.broadcast_view(&results, RACE_VIEW_SIZE, |view, revision| {
+            if revision >= MetaRevision::TimeGaps {
+                MetaFrame::encode(DataFormat::FmtSyncRaceData, &view)
+            } else {
+                MetaFrame::encode(DataFormat::FmtSyncRaceData, &view.iter().map(LegacyRaceProgress::from).collect::<Vec<_>>())
             }
         });
     }
@@ -311,11 +317,7 @@ impl RaceRoom {
         }
 
         if let Some(players) = self.get_player_nearby(tokenstr, 5.0) {
-            tokio::spawn(async move {
-                for player in players {
-                    player.notify_user_cmd(&RaceCmd::RaceCmdHorn).await;
-                }
-            });
+            broadcast(players.into_iter().map(|x| &self.players[x]), DataFormat::FmtRaceCommand, &RaceCmd::RaceCmdHorn);
         }
     }
 
@@ -324,71 +326,98 @@ impl RaceRoom {
             return;
         }
 
-        if Local::now().signed_duration_since(self.rank_tick) > chrono::Duration::seconds(1) {
+        if Local::now().signed_duration_since(self.rank_tick) > chrono::Duration::milliseconds(config::get().tick.ranking as i64) {
             self.rank_tick = Local::now();
 
-            let players = self.players.clone();
-            for (i, player) in self.players.iter_mut().enumerate() {
-                let players_temp = players.clone();
-                let player_pos = player.race_data.progress / player.race_data.stagelen * self.info.stage_len as f32;
-                let player_last_pos = player.last_race_data.progress / player.last_race_data.stagelen * self.info.stage_len as f32;
+            let stage_len = self.info.stage_len as f32;
+            let progress: Vec<(f32, f32)> = self.players.iter().map(|x| (x.race_data.progress, x.last_race_data.progress)).collect();
+            let mut moved = Vec::new();
+            let mut ridicules = Vec::new();
+            for (i, player) in self.players.iter().enumerate() {
+                let player_pos = player.race_data.progress / player.race_data.stagelen * stage_len;
+                let player_last_pos = player.last_race_data.progress / player.last_race_data.stagelen * stage_len;
 
                 if player_pos < player_last_pos || player.race_data.racetime < 10.0 {
                     continue; // player is in backward state or progress too short.
                 }
+                moved.push(i);
 
-                let winer: Vec<String> = players_temp[..i]
+                let winer: Vec<usize> = progress[..i]
                     .iter()
-                    .filter(|x| {
-                        let pos = x.race_data.progress / player.race_data.stagelen * self.info.stage_len as f32;
-                        let last_pos = x.last_race_data.progress / player.last_race_data.stagelen * self.info.stage_len as f32;
+                    .enumerate()
+                    .filter(|(_, (progress, last_progress))| {
+                        let pos = progress / player.race_data.stagelen * stage_len;
+                        let last_pos = last_progress / player.last_race_data.stagelen * stage_len;
                         pos > player_pos && last_pos < player_last_pos
                     })
-                    .map(|x| x.profile_name.clone())
+                    .map(|(index, _)| index)
                     .collect();
 
-                if winer.len() > 0 {
-                    let mut ridicules = MetaRaceRidicule::default();
-                    ridicules.players = winer;
-            
-                    if Local::now().signed_duration_since(player.lastridicule) > chrono::Duration::seconds(10) {
-                        let mut playerc = player.clone();
-                        tokio::spawn(async move {
-                            info!("notify ridicule to player: {} with: {:?}", playerc.profile_name, ridicules);
-                            playerc.notify_ridicule(&ridicules).await;
-                        });
-                        player.lastridicule = Local::now();
-                    }
+                if !winer.is_empty() && Local::now().signed_duration_since(player.lastridicule) > chrono::Duration::seconds(10) {
+                    let mut ridicule = MetaRaceRidicule::default();
+                    ridicule.players = winer.into_iter().map(|x| self.players[x].profile_name.clone()).collect();
+                    ridicules.push((i, ridicule));
                 }
+            }
+
+            for (i, ridicule) in ridicules {
+                let player = &mut self.players[i];
+                info!("notify ridicule to player: {} with: {:?}", player.profile_name, ridicule);
+                player.notify_ridicule(&ridicule);
+                player.lastridicule = Local::now();
+            }
 
+            for i in moved {
+                let player = &mut self.players[i];
                 player.last_race_data = player.race_data.clone();
             }
         }
     }
 
-    pub fn notify_all_players_race_result(&mut self) {
+    pub fn store_all_players_race_result(&mut self) {
         if self.is_empty() {
             return;
         }
 
         self.sort_players_by_time();
-        let results = self.get_race_result();
-        let players = self.players.clone();
-        tokio::spawn(async move {
-            for player in players {
-                player.notify_result(&results).await;
-            }
-        });
-    }
-
-    pub fn store_all_players_race_result(&mut self) {
-        if self.is_empty() {
-            return;
+        let info = self.info.clone();
+        let flags: Vec<TelemetryFlag> = self.players.iter()
+            .filter_map(|x| x.telemetry.get_flag().map(|reason| TelemetryFlag { name: x.profile_name.clone(), reason: reason.clone() }))
+            .collect();
+
+        // flagged runs score nothing and rank behind every valid run.
+        let (mut results, mut flagged): (Vec<MetaRaceResult>, Vec<MetaRaceResult>) = self.get_race_result()
+            .into_iter().partition(|x| !flags.iter().any(|flag| flag.name == x.profile_name));
+        if let Some(leader) = results.first().map(|x| x.finishtime) {
+            results.iter_mut().for_each(|x| x.difftime = x.finishtime - leader);
         }
-
-        let results = self.get_race_result();
+        self.scoring.score(self.deftime, &mut results);
+        flagged.iter_mut().for_each(|x| x.score = 0);
+        results.append(&mut flagged);
+        let rated = self.scoring.is_rated();
+        self.live.update_race_result(&results);
+        metrics::get().race_dnf.inc_by(results.iter().filter(|x| x.finishtime == 3600.0f32).count() as u64);
+        let pages = results.len().div_ceil(RACE_VIEW_SIZE) as u32;
+        let frames: Vec<MetaFrame> = results.chunks(RACE_VIEW_SIZE).enumerate()
+            .filter_map(|(page, chunk)| MetaFrame::encode(DataFormat::FmtSyncRaceResult, &MetaRaceResultPage { page: page as u32, pages, results: chunk.to_vec() }))
+            .collect();
+        // clients before result pages take all results in one frame.
+        let legacy = MetaFrame::encode(DataFormat::FmtSyncRaceResult, &results);
+        let writers: Vec<MetaWriter> = self.get_audience().filter_map(|x| x.writer.clone()).collect();
+        let db = self.db.clone();
         tokio::spawn(async move {
-            db::RaceDB::default().on_race_finished(&results).await;
+            if let Some(db) = db {
+                if let Err(e) = db.on_race_finished(&info, &results, &flags, rated).await {
+                    error!("Failed to store race {} result: {}", info.name, e);
+                }
+            }
+            for writer in &writers {
+                if writer.get_revision() >= MetaRevision::ResultPages {
+                    frames.iter().for_each(|x| { writer.send(x); });
+                } else if let Some(frame) = &legacy {
+                    writer.send(frame);
+                }
+            }
         });
     }
 
@@ -411,68 +440,125 @@ impl RaceRoom {
                 self.room_state = RoomState::RoomFree;
             }
         }
+        self.live.update_room_state(&self.room_state);
+    }
+
+    fn drop_players(&mut self, tokens: &[String]) {
+        let (dropped, players): (Vec<RacePlayer>, Vec<RacePlayer>) = self.players.drain(..).partition(|x| tokens.contains(&x.tokenstr));
+        for player in dropped {
+            info!("drop player {} from {}, timeout in state {:?}", player.profile_name, self.info.name, player.state);
+            self.dropped.push(player);
+        }
+        self.players = players;
+    }
+
+    fn retire_players(&mut self, tokens: &[String]) {
+        for player in self.players.iter_mut().filter(|x| tokens.contains(&x.tokenstr)) {
+            info!("retire player {} from {}, timeout in state {:?}", player.profile_name, self.info.name, player.state);
+            player.retire();
+        }
     }
 
     pub fn update_race_state(&mut self) {
-        match self.race_state {
-            RoomRaceState::RoomRaceBegin => {
-                info!("notify prepare game: {}", self.info.name);
-                self.reset_all_players_state();
-                self.notify_all_players_prepare();
-                self.race_state = RoomRaceState::RoomRacePrepare;
+        let players: Vec<PlayerStatus> = self.players.iter().map(|x| PlayerStatus {
+            token: x.tokenstr.clone(),
+            state: x.state.clone(),
+            lastupdate: x.lastupdate,
+            disconnected: x.disconnected,
+        }).collect();
+
+        let last_state = self.race.get_state().clone();
+        let cmds = self.race.update(Local::now(), &players, &config::get().timeout);
+        if &last_state != self.race.get_state() {
+            trace!("room {} race state {:?} -> {:?}", self.info.name, last_state, self.race.get_state());
+            if self.race.is_end() && last_state != RoomRaceState::RoomRaceExiting {
+                metrics::get().race_aborted();
             }
-            RoomRaceState::RoomRacePrepare => {
-                if self.is_all_players_ready() {
-                    self.race_state = RoomRaceState::RoomRaceReady;
+        }
+        for cmd in cmds {
+            match cmd {
+                LifecycleCmd::ResetPlayers => self.reset_all_players_state(),
+                LifecycleCmd::NotifyPrepare => {
+                    info!("notify prepare game: {}", self.info.name);
+                    metrics::get().race_started();
+                    self.notify_all_players_prepare();
                 }
-            }
-            RoomRaceState::RoomRaceReady => {
-                info!("notify load game: {}", self.info.name);
-                self.notify_all_players_load();
-                self.race_state = RoomRaceState::RoomRaceLoading;
-            }
-            RoomRaceState::RoomRaceLoading => {
-                if self.is_all_players_loaded() {
-                    self.race_state = RoomRaceState::RoomRaceLoaded;
+                LifecycleCmd::NotifyLoad => {
+                    info!("notify load game: {}", self.info.name);
+                    self.notify_all_players_load();
                 }
-            }
-            RoomRaceState::RoomRaceLoaded => {
-                info!("notify start game: {}", self.info.name);
-                self.notify_all_players_start();
-                self.race_state = RoomRaceState::RoomRaceStarting;
-            }
-            RoomRaceState::RoomRaceStarting => {
-                if self.is_all_players_started() {
-                    self.race_state = RoomRaceState::RoomRaceStarted;
+                LifecycleCmd::NotifyStart => {
+                    info!("notify start game: {}", self.info.name);
+                    self.notify_all_players_start();
                 }
-            }
-            RoomRaceState::RoomRaceStarted => {
-                info!("notify exchange data: {}", self.info.name);
-                self.notify_all_players_upload();
-                self.race_state = RoomRaceState::RoomRaceRunning;
-            }
-            RoomRaceState::RoomRaceRunning => {
-                self.notify_all_players_race_data();
-                self.notify_all_players_race_ridicule();
-                if self.is_all_players_finish() {
-                    self.race_state = RoomRaceState::RoomRaceFinished;
+                LifecycleCmd::NotifyUpload => {
+                    info!("notify exchange data: {}", self.info.name);
+                    self.notify_all_players_upload();
+                    let limit = self.get_race_limit();
+                    self.race.set_race_limit(limit);
+                    self.players.iter_mut().for_each(|x| {
+                        x.lastupdate = Local::now();
+                        x.telemetry.start(Local::now());
+                        x.trace = DistanceTrace::default();
+                    });
                 }
-            }
-            RoomRaceState::RoomRaceFinished => {
-                info!("notify finished results: {}", self.info.name);
-                self.notify_all_players_race_result();
-                self.store_all_players_race_result();
-                self.race_state = RoomRaceState::RoomRaceExiting;
-            }
-            RoomRaceState::RoomRaceExiting => {
-                if self.is_all_players_exitmenu() {
-                    self.race_state = RoomRaceState::RoomRaceEnd;
+                LifecycleCmd::NotifyRaceData => {
+                    self.notify_all_players_race_data();
+                    self.notify_all_players_race_ridicule();
                 }
+                LifecycleCmd::StoreResult => {
+                    info!("notify finished results: {}", self.info.name);
+                    metrics::get().race_finished();
+                    self.store_all_players_race_result();
+                }
+                LifecycleCmd::DropPlayers(tokens) => self.drop_players(&tokens),
+                LifecycleCmd::RetirePlayers(tokens) => self.retire_players(&tokens),
+                LifecycleCmd::RestoreDropped => self.restore_dropped_players(),
             }
-            RoomRaceState::RoomRaceEnd => {
-                self.race_state = RoomRaceState::RoomRaceInit;
-            }
-            _ => {}
         }
+
+        let states = self.players.iter().map(|x| x.get_user_state()).collect();
+        self.live.update_race_state(states);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn racer(room: &mut RaceRoom, name: &str) -> String {
+        let token = uuid::Uuid::new_v4().to_string();
+        room.push_player(RacePlayer::new(&token, &name.to_string()));
+        token
     }
-}
\ No newline at end of file
+
+    fn upload(room: &mut RaceRoom, token: &String, progress: f32, finishtime: f32) {
+        let data = MetaRaceData { racetime: 60.0, progress, stagelen: 5000.0, finishtime, ..Default::default() };
+        room.get_player(token).unwrap().update_race_data(data);
+    }
+
+    #[test]
+    fn retired_player_keeps_uploading() {
+        let mut room = RaceRoom::default();
+        room.set_scoring(&RaceScoring::WrcPoints);
+        let finisher = racer(&mut room, "finisher");
+        let stalled = racer(&mut room, "stalled");
+
+        upload(&mut room, &finisher, 5000.0, 180.0);
+        room.get_player(&finisher).unwrap().update_state(RaceState::RaceFinished);
+        room.retire_players(std::slice::from_ref(&stalled));
+        upload(&mut room, &stalled, 4000.0, 0.0);
+        room.get_player(&stalled).unwrap().update_state(RaceState::RaceRunning);
+
+        let player = room.get_player(&stalled).unwrap();
+        assert_eq!(player.state, RaceState::RaceRetired);
+        assert_eq!(player.race_data.finishtime, 3600.0);
+
+        room.sort_players_by_time();
+        let mut results = room.get_race_result();
+        room.scoring.score(room.deftime, &mut results);
+        assert_eq!(results[0].profile_name, "finisher");
+        assert_eq!(results[1].profile_name, "stalled");
+        assert_eq!(results[1].score, 0);
+    }
+}
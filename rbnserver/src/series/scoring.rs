@@ -0,0 +1,102 @@
+use rbnproto::httpapi::RaceScoring;
+use rbnproto::metaapi::MetaRaceResult;
+
+/// Decide the points of a finished race, results are sorted by finish time
+/// and retired players carry a 3600 seconds finish time, deftime is the
+/// default time of the stage in seconds.
+pub trait ScoringPolicy {
+    fn score(&self, deftime: Option<f32>, results: &mut Vec<MetaRaceResult>);
+
+    /// Whether the race counts towards the skill rating of the players.
+    fn is_rated(&self) -> bool {
+        true
+    }
+}
+
+fn is_retired(result: &MetaRaceResult) -> bool {
+    result.finishtime == 3600.0f32
+}
+
+pub struct NoScore;
+
+impl ScoringPolicy for NoScore {
+    fn score(&self, _deftime: Option<f32>, results: &mut Vec<MetaRaceResult>) {
+        results.iter_mut().for_each(|x| x.score = 0);
+    }
+
+    fn is_rated(&self) -> bool {
+        false
+    }
+}
+
+/// Custom points tables award at most the points of the WRC table, the
+/// points of every race go to the global score of the players.
+static CUSTOM_MAX_PLACES: usize = 32;
+static CUSTOM_MAX_POINTS: i32 = 25;
+
+/// Whether a room owner may race with the scoring.
+pub fn is_valid_scoring(scoring: &RaceScoring) -> bool {
+    match scoring {
+        RaceScoring::Custom(points) => {
+            !points.is_empty() && points.len() <= CUSTOM_MAX_PLACES && points.iter().all(|x| (0..=CUSTOM_MAX_POINTS).contains(x))
+        }
+        _ => true,
+    }
+}
+
+pub struct PointsTable {
+    points: Vec<i32>,
+}
+
+impl PointsTable {
+    pub fn wrc() -> Self {
+        Self { points: vec![25, 18, 15, 12, 10, 8, 6, 4, 2, 1] }
+    }
+
+    pub fn custom(points: &[i32]) -> Self {
+        Self { points: points.to_vec() }
+    }
+}
+
+impl ScoringPolicy for PointsTable {
+    fn score(&self, _deftime: Option<f32>, results: &mut Vec<MetaRaceResult>) {
+        for (i, result) in results.iter_mut().enumerate() {
+            if is_retired(result) {
+                result.score = 0;
+            } else {
+                result.score = self.points.get(i).cloned().unwrap_or_default();
+            }
+        }
+    }
+}
+
+/// 100 points for matching the stage default time, more for beating it.
+pub struct TimeBased;
+
+impl ScoringPolicy for TimeBased {
+    fn score(&self, deftime: Option<f32>, results: &mut Vec<MetaRaceResult>) {
+        let deftime = deftime.unwrap_or_default();
+        for result in results.iter_mut() {
+            if is_retired(result) || deftime <= 0.0 || result.finishtime <= 0.0 {
+                result.score = 0;
+            } else {
+                result.score = (deftime / result.finishtime * 100.0).round() as i32;
+            }
+        }
+    }
+}
+
+pub fn build_scoring(scoring: &RaceScoring) -> Box<dyn ScoringPolicy + Send + Sync> {
+    match scoring {
+        RaceScoring::NoScore => Box::new(NoScore),
+        RaceScoring::WrcPoints => Box::new(PointsTable::wrc()),
+        RaceScoring::TimeBased => Box::new(TimeBased),
+        RaceScoring::Custom(points) => Box::new(PointsTable::custom(points)),
+    }
+}
+
+impl Default for Box<dyn ScoringPolicy + Send + Sync> {
+    fn default() -> Self {
+        build_scoring(&RaceScoring::default())
+    }
+}
@@ -0,0 +1,234 @@
+use chrono::{DateTime, Local};
+use rbnproto::metaapi::MetaRaceData;
+use crate::config::TelemetryConfig;
+
+/// Part of the stage a finishing player must have covered.
+static FINISH_PROGRESS: f32 = 0.9;
+
+/// Seconds a split or finish time may lie outside the frames reporting it.
+static EVENT_SLACK: f32 = 1.0;
+
+/// A run left out of the scoring and the first check it failed.
+#[derive(Clone, Debug)]
+pub struct TelemetryFlag {
+    pub name: String,
+    pub reason: String,
+}
+
+/// Plausibility checks of the race data uploaded by one player, the race
+/// clock is held against the server clock from the upload command on.
+#[derive(Default, Clone, Debug)]
+pub struct TelemetryCheck {
+    start: Option<DateTime<Local>>,
+    anchor: Option<(DateTime<Local>, f32)>,
+    last: Option<(DateTime<Local>, MetaRaceData)>,
+    split1_progress: Option<f32>,
+    flag: Option<String>,
+}
+
+fn seconds(from: DateTime<Local>, to: DateTime<Local>) -> f32 {
+    (to - from).num_milliseconds() as f32 / 1000.0
+}
+
+/// A split or finish time shows up once, between the race times of the frames before and with it.
+fn check_event(name: &str, last: &MetaRaceData, data: &MetaRaceData, before: f32, after: f32) -> Result<(), String> {
+    if before > 0.0 && after != before {
+        return Err(format!("{} time changed from {:.2}s to {:.2}s", name, before, after));
+    }
+
+    if before == 0.0 && after > 0.0 && (after < last.racetime - EVENT_SLACK || after > data.racetime + EVENT_SLACK) {
+        return Err(format!("{} time {:.2}s outside of race time {:.2}s - {:.2}s", name, after, last.racetime, data.racetime));
+    }
+    Ok(())
+}
+
+impl TelemetryCheck {
+    /// Begin a new run, called when the race data upload is requested.
+    pub fn start(&mut self, now: DateTime<Local>) {
+        *self = Self { start: Some(now), ..Default::default() };
+    }
+
+    pub fn get_flag(&self) -> Option<&String> {
+        self.flag.as_ref()
+    }
+
+    /// Check the next frame of a started run, returns the reason once the run gets flagged.
+    pub fn check(&mut self, data: &MetaRaceData, now: DateTime<Local>, cfg: &TelemetryConfig) -> Option<String> {
+        if !cfg.enabled || self.start.is_none() || self.flag.is_some() {
+            return None;
+        }
+
+        if let Err(reason) = self.validate(data, now, cfg) {
+            self.flag = Some(reason.clone());
+            return Some(reason);
+        }
+        self.last = Some((now, data.clone()));
+        None
+    }
+
+    fn validate(&mut self, data: &MetaRaceData, now: DateTime<Local>, cfg: &TelemetryConfig) -> Result<(), String> {
+        let values = [data.speed, data.racetime, data.progress, data.stagelen, data.splittime1, data.splittime2, data.finishtime];
+        if values.iter().any(|x| !x.is_finite()) {
+            return Err("invalid number in race data".to_string());
+        }
+
+        if data.speed.abs() > cfg.max_speed {
+            return Err(format!("speed {:.0}km/h over {:.0}km/h", data.speed.abs(), cfg.max_speed));
+        }
+
+        if data.progress > data.stagelen + cfg.distance_slack {
+            return Err(format!("progress {:.0}m beyond stage length {:.0}m", data.progress, data.stagelen));
+        }
+
+        // the race clock never runs slower than the server clock since the upload,
+        // nor faster than the server clock since the first frame.
+        let elapsed = seconds(self.start.unwrap_or(now), now);
+        if data.racetime < elapsed - cfg.clock_slack {
+            return Err(format!("race time {:.1}s behind {:.1}s since the start", data.racetime, elapsed));
+        }
+        let (anchor_time, anchor_racetime) = *self.anchor.get_or_insert((now, data.racetime));
+        let ahead = data.racetime - anchor_racetime - seconds(anchor_time, now);
+        if ahead > cfg.clock_slack {
+            return Err(format!("race time {:.1}s ahead of the server clock", ahead));
+        }
+
+        let last = self.last.as_ref().map(|(time, data)| (*time, data.clone()));
+        let (last_time, last) = last.unwrap_or((now, MetaRaceData::default()));
+        if self.last.is_some() {
+            let moved = data.progress - last.progress;
+            if moved < -cfg.progress_backoff {
+                return Err(format!("progress went back {:.0}m", -moved));
+            }
+
+            let interval = seconds(last_time, now).max(data.racetime - last.racetime).max(0.0);
+            let reach = last.speed.abs().max(data.speed.abs()) / 3.6 * interval * 1.5 + cfg.distance_slack;
+            if moved > reach {
+                return Err(format!("moved {:.0}m in {:.2}s at {:.0}km/h", moved, interval, data.speed.abs()));
+            }
+        }
+
+        check_event("split 1", &last, data, last.splittime1, data.splittime1)?;
+        check_event("split 2", &last, data, last.splittime2, data.splittime2)?;
+        check_event("finish", &last, data, last.finishtime, data.finishtime)?;
+
+        if data.splittime2 > 0.0 && !(data.splittime1 > 0.0 && data.splittime1 < data.splittime2) {
+            return Err(format!("split times out of order {:.2}s, {:.2}s", data.splittime1, data.splittime2));
+        }
+
+        if data.splittime1 > 0.0 && self.split1_progress.is_none() {
+            self.split1_progress = Some(data.progress);
+        }
+        if let (Some(split1), true) = (self.split1_progress, last.splittime2 == 0.0 && data.splittime2 > 0.0) {
+            if data.progress < split1 {
+                return Err(format!("split 2 at {:.0}m before split 1 at {:.0}m", data.progress, split1));
+            }
+        }
+
+        if data.finishtime > 0.0 {
+            if data.finishtime <= data.splittime1 || data.finishtime <= data.splittime2 {
+                return Err(format!("finish time {:.2}s not after the split times", data.finishtime));
+            }
+            if data.progress < data.stagelen * FINISH_PROGRESS {
+                return Err(format!("finished at {:.0}m of {:.0}m", data.progress, data.stagelen));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(racetime: f32, progress: f32, speed: f32) -> MetaRaceData {
+        MetaRaceData { racetime, progress, speed, stagelen: 5000.0, ..Default::default() }
+    }
+
+    fn at(start: DateTime<Local>, seconds: f32) -> DateTime<Local> {
+        start + chrono::Duration::milliseconds((seconds * 1000.0) as i64)
+    }
+
+    /// Drive the stage at a steady 100km/h, one frame per second.
+    fn clean_run(start: DateTime<Local>) -> Vec<(DateTime<Local>, MetaRaceData)> {
+        let speed = 100.0;
+        let mut frames = vec![];
+        for second in 0..=180 {
+            let racetime = second as f32;
+            let mut data = frame(racetime, (racetime * speed / 3.6).min(5000.0), speed);
+            data.splittime1 = if racetime >= 60.0 { 60.0 } else { 0.0 };
+            data.splittime2 = if racetime >= 120.0 { 120.0 } else { 0.0 };
+            data.finishtime = if racetime >= 180.0 { 180.0 } else { 0.0 };
+            frames.push((at(start, racetime + 5.0), data));
+        }
+        frames
+    }
+
+    fn run(frames: &[(DateTime<Local>, MetaRaceData)], start: DateTime<Local>) -> TelemetryCheck {
+        let cfg = TelemetryConfig::default();
+        let mut check = TelemetryCheck::default();
+        check.start(start);
+        for (now, data) in frames {
+            check.check(data, *now, &cfg);
+        }
+        check
+    }
+
+    #[test]
+    fn clean_run_passes() {
+        let start = Local::now();
+        let check = run(&clean_run(start), start);
+        assert_eq!(check.get_flag(), None);
+    }
+
+    #[test]
+    fn unstarted_run_is_not_checked() {
+        let now = Local::now();
+        let mut check = TelemetryCheck::default();
+        assert_eq!(check.check(&frame(0.0, 4000.0, 999.0), now, &TelemetryConfig::default()), None);
+    }
+
+    #[test]
+    fn teleport_is_flagged() {
+        let start = Local::now();
+        let mut frames = clean_run(start);
+        frames[30].1.progress += 1000.0;
+        assert!(run(&frames, start).get_flag().unwrap().starts_with("moved"));
+    }
+
+    #[test]
+    fn progress_going_back_is_flagged() {
+        let start = Local::now();
+        let mut frames = clean_run(start);
+        frames.truncate(100);
+        frames.push((at(start, 105.0), frame(100.0, 100.0, 0.0)));
+        assert!(run(&frames, start).get_flag().unwrap().starts_with("progress went back"));
+    }
+
+    #[test]
+    fn slow_race_clock_is_flagged() {
+        let start = Local::now();
+        let mut frames = clean_run(start);
+        for (i, (now, _)) in frames.iter_mut().enumerate() {
+            *now = at(start, i as f32 * 1.2 + 5.0); // the client clock runs 20% slow.
+        }
+        assert!(run(&frames, start).get_flag().unwrap().contains("behind"));
+    }
+
+    #[test]
+    fn early_finish_time_is_flagged() {
+        let start = Local::now();
+        let mut frames = clean_run(start);
+        frames.last_mut().unwrap().1.finishtime = 150.0;
+        assert!(run(&frames, start).get_flag().unwrap().starts_with("finish time"));
+    }
+
+    #[test]
+    fn splits_out_of_order_are_flagged() {
+        let start = Local::now();
+        let mut frames = clean_run(start);
+        for (_, data) in frames.iter_mut().filter(|(_, x)| x.splittime2 > 0.0) {
+            data.splittime1 = 0.0;
+        }
+        assert!(run(&frames, start).get_flag().is_some());
+    }
+}
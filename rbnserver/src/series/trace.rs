@@ -0,0 +1,132 @@
+/// Meters between two kept samples, the latest frame is always kept.
+static TRACE_STEP: f32 = 2.0;
+
+/// Race time at which one player passed each distance of the stage, live
+/// gaps compare the times of two players at the same distance.
+#[derive(Default, Clone, Debug)]
+pub struct DistanceTrace {
+    samples: Vec<(f32, f32)>,
+}
+
+impl DistanceTrace {
+    /// Record a frame, the trace only moves forward along the stage.
+    pub fn record(&mut self, progress: f32, racetime: f32) {
+        if !progress.is_finite() || !racetime.is_finite() {
+            return;
+        }
+
+        if let Some(&(last_progress, last_time)) = self.samples.last() {
+            if progress <= last_progress || racetime < last_time {
+                return;
+            }
+        }
+
+        if let [.., before, last] = self.samples[..] {
+            if last.0 - before.0 < TRACE_STEP {
+                self.samples.pop();
+            }
+        }
+        self.samples.push((progress, racetime));
+    }
+
+    /// Race time at the distance, none if the player has not got there yet.
+    pub fn time_at(&self, progress: f32) -> Option<f32> {
+        let index = self.samples.partition_point(|x| x.0 < progress);
+        let (after_progress, after_time) = *self.samples.get(index)?;
+        if index == 0 {
+            return Some(after_time);
+        }
+
+        let (before_progress, before_time) = self.samples[index - 1];
+        let part = (progress - before_progress) / (after_progress - before_progress);
+        Some(before_time + (after_time - before_time) * part)
+    }
+}
+
+/// Seconds the chasing player reached its current distance after the player ahead did.
+pub fn time_gap(chaser: (f32, f32), ahead: &DistanceTrace) -> f32 {
+    let (progress, racetime) = chaser;
+    ahead.time_at(progress).map_or(0.0, |time| (racetime - time).max(0.0))
+}
+
+/// Indexes of at most limit entries of a list ordered by progress: the leader, the
+/// viewer and the cars nearest to the viewer on the road, in list order. Without a
+/// viewer the cars nearest to the leader are picked.
+pub fn select_view(progress: &[f32], viewer: Option<usize>, limit: usize) -> Vec<usize> {
+    if progress.len() <= limit {
+        return (0..progress.len()).collect();
+    }
+
+    let viewer = viewer.unwrap_or(0);
+    let mut picked = vec![0, viewer];
+    picked.dedup();
+    let mut nearest: Vec<usize> = (0..progress.len()).filter(|x| !picked.contains(x)).collect();
+    nearest.sort_by(|a, b| (progress[*a] - progress[viewer]).abs().total_cmp(&(progress[*b] - progress[viewer]).abs()));
+    picked.extend(nearest.into_iter().take(limit.saturating_sub(picked.len())));
+    picked.sort();
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drive at a steady speed in m/s, ten frames per second.
+    fn steady(speed: f32, seconds: f32) -> DistanceTrace {
+        let mut trace = DistanceTrace::default();
+        for frame in 0..=(seconds * 10.0) as u32 {
+            let racetime = frame as f32 / 10.0;
+            trace.record(racetime * speed, racetime);
+        }
+        trace
+    }
+
+    #[test]
+    fn time_is_interpolated_between_samples() {
+        let trace = steady(25.0, 60.0);
+        assert!((trace.time_at(1000.0).unwrap() - 40.0).abs() < 0.01);
+        assert!((trace.time_at(1001.3).unwrap() - 40.052).abs() < 0.01);
+        assert_eq!(trace.time_at(1600.0), None);
+    }
+
+    #[test]
+    fn latest_frame_is_kept() {
+        let mut trace = steady(25.0, 10.0);
+        trace.record(250.5, 10.02);
+        assert_eq!(trace.time_at(250.5), Some(10.02));
+    }
+
+    #[test]
+    fn going_back_is_ignored() {
+        let mut trace = steady(25.0, 10.0);
+        trace.record(100.0, 11.0);
+        trace.record(240.0, 12.0);
+        assert!((trace.time_at(100.0).unwrap() - 4.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn view_keeps_leader_viewer_and_nearest() {
+        let progress: Vec<f32> = (0..20).map(|x| 2000.0 - x as f32 * 100.0).collect();
+        assert_eq!(select_view(&progress, Some(12), 8), vec![0, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(select_view(&progress, Some(19), 8), vec![0, 13, 14, 15, 16, 17, 18, 19]);
+        assert_eq!(select_view(&progress, None, 8), (0..8).collect::<Vec<_>>());
+        assert_eq!(select_view(&progress[..5], Some(3), 8), (0..5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn view_prefers_cars_nearest_on_the_road() {
+        let progress = [5000.0, 3000.0, 2990.0, 2980.0, 1500.0, 1000.0, 990.0, 980.0, 500.0, 0.0];
+        assert_eq!(select_view(&progress, Some(5), 5), vec![0, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn gap_is_measured_at_the_same_distance() {
+        // the leader slows down for a hairpin after 500m, the chaser is 50m back.
+        let mut leader = steady(25.0, 20.0);
+        for frame in 1..=50 {
+            leader.record(500.0 + frame as f32 * 0.5, 20.0 + frame as f32 / 10.0);
+        }
+        assert!((time_gap((475.0, 21.0), &leader) - 2.0).abs() < 0.01);
+        assert_eq!(time_gap((600.0, 30.0), &leader), 0.0);
+    }
+}
@@ -1,302 +1,559 @@
 use chrono::{DateTime, Local};
 use log::{error, info};
-use rbnproto::httpapi::{RaceConfig, RaceConfigUpdate, RaceCreate, RaceInfoUpdate, RaceUserState, UserHeart, UserQuery, UserScore};
-use rbnproto::httpapi::{UserLogin, UserLogout, RaceInfo, RaceBrief};
+use rbnproto::httpapi::{RaceConfig, RaceConfigUpdate, RaceCreate, RaceInfoUpdate, RaceUserState, UserHeart, UserQuery, UserRefresh, UserRegister, UserClaim, UserScore, UserToken};
+use rbnproto::httpapi::{FlaggedRun, UserLogin, UserLogout, RaceInfo, RaceBrief, RaceDetail, RaceHistory, RaceHistoryQuery, RaceOverview, StageRecordQuery, StageRecords, UserRating};
 use rbnproto::metaapi::{RaceJoin, RaceUpdate, RaceAccess, MetaRaceData};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use uuid::Uuid;
-use crate::db;
-use crate::lobby::RaceLobby;
-use crate::player::LobbyPlayer;
+use crate::{auth, config, metrics};
+use crate::db::{DbResult, RaceDB};
+use crate::lobby::{LobbySession, RaceLobby};
+use crate::player::{LobbyPlayer, UserRole};
+use crate::series::actor::RoomHandle;
 use crate::series::customize::Customize;
 use crate::series::daily::Daily;
+use crate::series::live::LiveSubscription;
+use crate::series::randomer::game_data;
 use crate::series::Series;
+use crate::writer::MetaWriter;
 use std::collections::HashMap;
 use std::process::exit;
-use std::sync::Arc;
 use tera::Tera;
 
-#[derive(Default)]
+/// Shared by every http handler and meta connection, the lobby and the room
+/// registry have their own locks and the rooms run as their own tasks, no lock
+/// is ever held across database work.
 pub struct RacingServer {
-    pub tera: Tera,
-    tick_time: DateTime<Local>,
-    pub lobby: RaceLobby,
-    pub races: HashMap<String, Box<dyn Series + Send + Sync>>,
+    pub tera: RwLock<Tera>,
+    tick_time: Mutex<DateTime<Local>>,
+    lobby: Mutex<RaceLobby>,
+    races: RwLock<HashMap<String, RoomHandle>>,
+    db: RaceDB,
 }
 
 impl RacingServer {
+    pub fn new(db: RaceDB) -> Self {
+        Self {
+            tera: RwLock::default(),
+            tick_time: Mutex::default(),
+            lobby: Mutex::default(),
+            races: RwLock::default(),
+            db,
+        }
+    }
+
     pub fn init(mut self) -> Self {
-        self.tera = Tera::new("templates/**/*.html").expect("Failed to compile templates");
+        let templates = config::get().paths.get_template_dir().join("**").join("*.html");
+        *self.tera.get_mut() = Tera::new(templates.to_str().unwrap()).expect("Failed to compile templates");
         self.check_environment();
-        self.races.insert("Daily Challenge".to_string(), Box::new(Daily::default().init()));
-        self.races.insert("Time Trial".to_string(), Box::new(Daily::named("Time Trial").init()));
-        self.races.insert("Practice".to_string(), Box::new(Daily::named("Practice").init()));
+        for series in &config::get().series {
+            let daily = Box::new(Daily::with_config(series).with_database(&self.db).init());
+            self.races.get_mut().insert(series.name.clone(), RoomHandle::spawn(series.name.clone(), daily));
+        }
         self
     }
 
-    pub fn dynamic_reload_templates(&mut self) {
-        if Local::now().signed_duration_since(self.tick_time) > chrono::Duration::seconds(1) {
-            self.tick_time = Local::now();
-            self.tera.full_reload().expect("Failed to watch tera template directory.");
+    pub async fn dynamic_reload_templates(&self) {
+        let mut tick_time = self.tick_time.lock().await;
+        if Local::now().signed_duration_since(*tick_time) > chrono::Duration::seconds(1) {
+            *tick_time = Local::now();
+            self.tera.write().await.full_reload().expect("Failed to watch tera template directory.");
         }
     }
 
-    pub fn check_environment(&mut self) {
-        let path = std::env::current_exe().unwrap().parent().unwrap().join("rsfdata");
+    pub fn check_environment(&self) {
+        let path = config::get().paths.get_data_dir();
         if !path.exists() || !path.is_dir() {
-            error!("Fatal Error, Make sure rsfdata directory is exists in your app's running path.");
+            error!("Fatal Error, Make sure rsfdata directory is exists in your app's running path or configured data_dir.");
             exit(1);
         }
     }
 
-    pub fn is_race_exist(&mut self, name: &String) -> bool {
-        self.races.contains_key(name)
+    async fn get_room(&self, name: &String) -> Option<RoomHandle> {
+        self.races.read().await.get(name).cloned()
+    }
+
+    async fn get_rooms(&self) -> Vec<RoomHandle> {
+        self.races.read().await.values().cloned().collect()
+    }
+
+    async fn get_lobby_player(&self, tokenstr: &str) -> Option<LobbyPlayer> {
+        let token = Uuid::parse_str(tokenstr).ok()?;
+        self.lobby.lock().await.get_player(token).cloned()
+    }
+
+    /// Rooms close themselves once they need recycle.
+    pub async fn recycle_invalid_races(&self) {
+        self.races.write().await.retain(|_k, v| !v.is_closed());
+    }
+
+    pub async fn recycle_invalid_players(&self) {
+        let expired = self.lobby.lock().await.check_players();
+        for token in expired {
+            self.force_leave_race(&token).await;
+        }
+    }
+
+    pub async fn force_leave_race(&self, token: &Uuid) {
+        for room in self.get_rooms().await {
+            let tokenstr = token.to_string();
+            room.call(move |race| race.leave(&tokenstr)).await;
+        }
     }
 
-    pub fn recycle_invalid_races(&mut self) {
-        self.races.retain(|_k, v| !v.need_recycle());
+    pub async fn user_register(&self, user: UserRegister) -> bool {
+        if !auth::is_register_valid(&user) {
+            return false;
+        }
+
+        if let Some(hashed) = auth::hash_passwd(&user.passwd) {
+            match self.db.on_user_register(&user.name, &hashed, user.claim.as_ref()).await {
+                Ok(true) => {
+                    info!("User {} registered", user.name);
+                    return true;
+                }
+                Ok(false) => {}
+                Err(e) => error!("Failed to register user {}: {}", user.name, e),
+            }
+        }
+        false
     }
 
-    pub fn recycle_invalid_players(&mut self) {
-        self.lobby.check_players();
-        for (_, race) in self.races.iter_mut() {
-            race.check_players(&self.lobby);
+    /// Hand out a one-time code to claim the account with a new password.
+    pub async fn user_reset(&self, name: &String) -> DbResult<Option<UserClaim>> {
+        let claim = auth::new_claim_code();
+        let Some(hashed) = auth::hash_passwd(&claim) else {
+            return Ok(None);
+        };
+
+        if !self.db.on_user_reset(name, &hashed).await? {
+            return Ok(None);
         }
+        info!("User {} reset for claim", name);
+        Ok(Some(UserClaim { name: name.clone(), claim }))
     }
 
-    pub fn force_leave_race(&mut self, token: &Uuid) {
-        for (_, race) in self.races.iter_mut() {
-            race.leave(&token.to_string());
+    /// Only a confirmed missing user, clients register on it.
+    pub async fn is_user_unknown(&self, name: &String) -> bool {
+        matches!(self.db.query_user_passwd(name).await, Ok(None))
+    }
+
+    pub async fn user_login(&self, user: UserLogin) -> Option<UserToken> {
+        let (hashed, role) = match self.db.query_user_passwd(&user.name).await {
+            Ok(passwd) => passwd?,
+            Err(e) => {
+                error!("Failed to query user {}: {}", user.name, e);
+                return None;
+            }
+        };
+        if !auth::verify_passwd(&user.passwd, &hashed) {
+            return None;
+        }
+
+        // replace the old session under one lock, concurrent logins must not leave two sessions.
+        let (previous, session) = {
+            let mut lobby = self.lobby.lock().await;
+            let previous = lobby.get_token_by_name(user.name.clone());
+            if let Some(token) = &previous {
+                lobby.pop_player(token);
+                lobby.revoke_sessions(token);
+            }
+            (previous, self.open_session(&mut lobby, &user.name, &role))
+        };
+
+        if let Some(token) = &previous {
+            self.force_leave_race(token).await;
         }
+        Some(session)
     }
 
-    pub async fn user_login(&mut self, user: UserLogin) -> Option<String> {
-        if user.passwd != "simrallycn" {
+    pub async fn user_refresh(&self, refresh: UserRefresh) -> Option<UserToken> {
+        let refresh = Uuid::parse_str(&refresh.refresh).ok()?;
+        let mut lobby = self.lobby.lock().await;
+        let session = lobby.pop_session(&refresh)?;
+        if session.is_expired() {
             return None;
         }
 
-        if let Some(token) = &self.lobby.get_token_by_name(user.name.clone()) {
-            self.force_leave_race(token);
-            return Some(token.to_string());
+        if let Some(player) = lobby.get_player(session.token) {
+            player.renew();
+            let expire = player.get_expire().timestamp();
+            let refresh = Uuid::new_v4();
+            lobby.push_session(refresh, session.clone());
+            return Some(UserToken { token: session.token.to_string(), refresh: refresh.to_string(), expire });
         }
 
+        Some(self.open_session(&mut lobby, &session.name, &session.role))
+    }
+
+    fn open_session(&self, lobby: &mut RaceLobby, name: &String, role: &UserRole) -> UserToken {
         let token = Uuid::new_v4();
+        let refresh = Uuid::new_v4();
         let tokenstr = token.to_string();
-        let player: LobbyPlayer = LobbyPlayer::new(&tokenstr, &user.name);
-        db::RaceDB::default().on_user_login(&player).await;
+        let mut player: LobbyPlayer = LobbyPlayer::new(&tokenstr, name);
+        player.role = role.clone();
+        let expire = player.get_expire().timestamp();
         info!("User {} login with token {}", player.profile_name, tokenstr);
-        self.lobby.push_player(token, player);
-        return Some(tokenstr);
+        lobby.push_player(token, player);
+        lobby.push_session(refresh, LobbySession::new(name, role, &token));
+        UserToken { token: tokenstr, refresh: refresh.to_string(), expire }
+    }
+
+    pub async fn authorize(&self, tokenstr: &str) -> Option<LobbyPlayer> {
+        let player = self.get_lobby_player(tokenstr).await?;
+        if player.is_expired() {
+            return None;
+        }
+        Some(player)
+    }
+
+    /// Meta frames carry the token, checked for expiry like the http bearer token.
+    async fn is_authorized(&self, tokenstr: &str) -> bool {
+        let Ok(token) = Uuid::parse_str(tokenstr) else {
+            return false;
+        };
+        self.lobby.lock().await.get_player(token).is_some_and(|x| !x.is_expired())
     }
 
-    pub fn user_heartbeat(&mut self, user: UserHeart) {
+    pub async fn user_heartbeat(&self, user: UserHeart) {
         if let Ok(token) = Uuid::parse_str(&user.token) {
-            if let Some(player) = self.lobby.get_player(token) {
+            if let Some(player) = self.lobby.lock().await.get_player(token) {
                 player.set_alive();
             }
         }
     }
 
-    pub fn user_logout(&mut self, user: UserLogout) -> bool {
+    pub async fn user_logout(&self, user: UserLogout) -> bool {
         if let Ok(token) = Uuid::parse_str(&user.token) {
-            if self.lobby.is_player_exist(Some(&token), None) {
-                self.lobby.pop_player(&token);
+            let exist = self.lobby.lock().await.is_player_exist(Some(&token), None);
+            if exist {
+                self.force_leave_race(&token).await;
+                let mut lobby = self.lobby.lock().await;
+                lobby.pop_player(&token);
+                lobby.revoke_sessions(&token);
                 return true;
             }
         }
         return false;
     }
 
-    pub async fn get_user_score(&mut self, tokenstr: &String) -> Option<UserScore> {
-        if let Ok(token) = Uuid::parse_str(tokenstr) {
-            if let Some(player) = self.lobby.get_player(token) {
-                return db::RaceDB::default().query_user_score(player).await;
-            }
+    pub async fn get_user_score(&self, tokenstr: &String) -> DbResult<Option<UserScore>> {
+        match self.get_lobby_player(tokenstr).await {
+            Some(player) => self.db.query_user_score(&player.profile_name).await,
+            None => Ok(None),
         }
+    }
 
-        None
+    pub async fn get_all_user_score(&self) -> DbResult<Vec<UserScore>> {
+        self.db.query_all_user_score().await
+    }
+
+    pub async fn get_user_history(&self, name: &String, query: &RaceHistoryQuery) -> DbResult<RaceHistory> {
+        let size = if query.size == 0 { 20 } else { query.size.min(100) };
+        self.db.query_user_history(name, query.page, size).await
+    }
+
+    pub async fn get_user_rating(&self, name: &String) -> DbResult<Option<UserRating>> {
+        self.db.query_user_rating(name).await
     }
 
-    pub async fn get_all_user_score(&mut self) -> Vec<UserScore> {
-        db::RaceDB::default().query_all_user_score().await
+    pub async fn get_flagged_runs(&self, query: &RaceHistoryQuery) -> DbResult<Vec<FlaggedRun>> {
+        let size = if query.size == 0 { 20 } else { query.size.min(100) };
+        self.db.query_flagged_runs(query.page, size).await
     }
 
-    pub fn get_race_news(&mut self) -> String {
+    pub async fn get_race_detail(&self, race_id: i64) -> DbResult<Option<RaceDetail>> {
+        self.db.query_race_detail(race_id).await
+    }
+
+    pub async fn get_stage_records(&self, stage_id: u32, query: &StageRecordQuery) -> DbResult<StageRecords> {
+        let limit = if query.limit == 0 { 20 } else { query.limit.min(100) };
+        let mut group_cars = vec![];
+        if let Some(group) = &query.car_group {
+            group_cars = game_data().get_group_cars(group);
+        }
+        self.db.query_stage_records(stage_id, query, &group_cars, limit).await
+    }
+
+    pub async fn get_race_news(&self) -> String {
         let mut count = 0u32;
-        self.races.iter_mut().for_each(|(_, race)| {
-            count += race.get_players_counts();
-        });
+        for room in self.get_rooms().await {
+            count += room.call(|race| race.get_players_counts()).await.unwrap_or_default();
+        }
 
         format!("{} players online, enter [Time Trial] or [Practice] to Join Race !!!", count)
     }
 
-    pub fn get_race_list(&mut self) -> Option<Vec<RaceBrief>> {
-        if self.races.is_empty() {
-            return None;
-        }
-    
+    pub async fn get_race_list(&self) -> Option<Vec<RaceBrief>> {
         let mut racelist = vec![];
-        for (_name, race) in self.races.iter_mut() {
-            let brief = race.get_race_brief();
-            racelist.push(brief);
+        for room in self.get_rooms().await {
+            if let Some(brief) = room.call(|race| race.get_race_brief()).await {
+                racelist.push(brief);
+            }
         }
 
+        if racelist.is_empty() {
+            return None;
+        }
         Some(racelist)
     }
 
-    pub fn get_race_info(&mut self, name: &String) -> Option<RaceInfo> {
-        if let Some(race) = self.races.get_mut(name) {
-            return Some(race.get_race_config());
+    pub async fn get_race_info(&self, name: &String) -> Option<RaceInfo> {
+        self.get_room(name).await?.call(|race| race.get_race_config()).await
+    }
+
+    pub async fn get_race_overview(&self) -> Vec<RaceOverview> {
+        let mut overview = vec![];
+        for room in self.get_rooms().await {
+            let room = room.call(|race| RaceOverview {
+                brief: race.get_race_brief(),
+                info: race.get_race_config(),
+                players: race.get_players_state(),
+                next_start: race.get_next_start().map(|x| x.timestamp()),
+            }).await;
+            overview.extend(room);
         }
+        overview.sort_by(|a, b| a.brief.name.cmp(&b.brief.name));
+        overview
+    }
 
-        None
+    pub async fn update_metrics(&self) {
+        let metrics = metrics::get();
+        metrics.online_players.set(self.lobby.lock().await.get_players_counts() as i64);
+        metrics.series_players.reset();
+        for room in self.get_rooms().await {
+            if let Some((brief, counts)) = room.call(|race| (race.get_race_brief(), race.get_players_counts())).await {
+                let state = format!("{:?}", brief.state);
+                metrics.series_players.with_label_values(&[&brief.name, &state]).set(counts as i64);
+            }
+        }
+    }
+
+    pub async fn subscribe_race_live(&self, name: &String) -> Option<LiveSubscription> {
+        self.get_room(name).await?.call(|race| race.subscribe_live()).await
     }
 
-    pub fn update_race_info(&mut self, update: RaceInfoUpdate) -> bool {
-        if let Ok(token) = Uuid::parse_str(&update.token) {
-            if self.lobby.is_player_exist(Some(&token), None) {
-                if let Some(race) = self.races.get_mut(&update.info.name) {
-                    race.update_race_config(update.info);
+    /// Admins manage every room, owners only manage their own unprotected room.
+    pub async fn is_race_manageable(&self, tokenstr: &String, roomname: &String) -> bool {
+        if let Some(player) = self.get_lobby_player(tokenstr).await {
+            if let Some(room) = self.get_room(roomname).await {
+                if player.is_admin() {
                     return true;
                 }
+                let owned = room.call(move |race| !race.is_protected() && race.get_race_config().owner == player.profile_name).await;
+                return owned.unwrap_or_default();
             }
         }
-        return false;
+        false
     }
 
-    pub fn get_player_race_config(&mut self, query: &UserQuery) -> Option<RaceConfig> {
-        for (_, race) in self.races.iter_mut() {
-            if let Some(config) = race.get_player_config(&query.token) {
+    pub async fn update_race_info(&self, mut update: RaceInfoUpdate) -> bool {
+        if !self.is_race_manageable(&update.token, &update.info.name).await {
+            return false;
+        }
+
+        if let Some(room) = self.get_room(&update.info.name).await {
+            return room.call(move |race| {
+                update.info.owner = race.get_race_config().owner;
+                race.update_race_config(update.info);
+            }).await.is_some();
+        }
+        false
+    }
+
+    pub async fn get_player_race_config(&self, query: &UserQuery) -> Option<RaceConfig> {
+        for room in self.get_rooms().await {
+            let token = query.token.clone();
+            if let Some(Some(config)) = room.call(move |race| race.get_player_config(&token)).await {
                 return Some(config);
             }
         }
         None
     }
 
-    pub fn update_player_race_config(&mut self, update: RaceConfigUpdate) -> bool {
-        for (_, race) in self.races.iter_mut() {
-            if race.update_player_config(&update.token, update.cfg.clone()) {
+    pub async fn update_player_race_config(&self, update: RaceConfigUpdate) -> bool {
+        for room in self.get_rooms().await {
+            let update = update.clone();
+            if let Some(true) = room.call(move |race| race.update_player_config(&update.token, update.cfg)).await {
                 return true;
             }
         }
         return false;
     }
 
-    pub fn get_race_started(&mut self, name: &String) -> Option<bool> {
-        if let Some(race) = self.races.get_mut(name) {
-            return Some(race.is_started());
-        }
-
-        None
+    pub async fn get_race_started(&self, name: &String) -> Option<bool> {
+        self.get_room(name).await?.call(|race| race.is_started()).await
     }
 
-    pub fn set_race_started(&mut self, access: &RaceAccess) -> bool {
-        if let Ok(token) = Uuid::parse_str(&access.token) {
-            if self.lobby.is_player_exist(Some(&token), None) {
-                if let Some(race) = self.races.get_mut(&access.room) {
-                    return race.set_start();
-                }
+    pub async fn set_race_started(&self, access: &RaceAccess) -> bool {
+        if self.is_authorized(&access.token).await {
+            if let Some(room) = self.get_room(&access.room).await {
+                return room.call(|race| race.set_start()).await.unwrap_or_default();
             }
         }
         return false;
     }
 
-    pub fn get_race_userstate(&mut self, name: &String) -> Option<Vec<RaceUserState>> {
-        if let Some(race) = self.races.get_mut(name) {
-            return Some(race.get_players_state());
-        }
-
-        None
+    pub async fn get_race_userstate(&self, name: &String) -> Option<Vec<RaceUserState>> {
+        self.get_room(name).await?.call(|race| race.get_players_state()).await
     }
 
-    pub fn create_race(&mut self, create: RaceCreate) -> bool {
-        if self.is_race_exist(&create.info.name) {
-            return true;
+    pub async fn create_race(&self, create: RaceCreate) -> bool {
+        if self.get_room(&create.info.name).await.is_some() {
+            return false;
         }
 
         if let Ok(token) = Uuid::parse_str(&create.token.as_str()) {
-            self.force_leave_race(&token);
-            if let Some(player) = self.lobby.get_player(token) {
-                let mut raceroom = Customize::default();
-                raceroom.set_limit(8);
-                raceroom.update_race_config(create.info.clone());
+            self.force_leave_race(&token).await;
+            if let Some(player) = self.get_lobby_player(&create.token).await {
+                let mut raceroom = Customize::default().with_database(&self.db);
+                let mut info = create.info.clone();
+                info.owner = player.profile_name.clone();
+                raceroom.set_limit(create.limit.unwrap_or(config::get().room_limit) as usize);
+                raceroom.set_scoring(&create.scoring);
+                raceroom.update_race_config(info);
                 if create.locked {
                     if let Some(passwd) = &create.passwd {
                         raceroom.lock_with_passwd(passwd);
                     }
                 }
                 raceroom.join(&player);
-                self.races.insert(create.info.name, Box::new(raceroom));
+                // another create may have taken the name while the room was built.
+                let mut races = self.races.write().await;
+                if races.contains_key(&create.info.name) {
+                    return false;
+                }
+                races.insert(create.info.name.clone(), RoomHandle::spawn(create.info.name, Box::new(raceroom)));
                 return true;
             }
         }
         return false;
     }
 
-    pub fn join_race(&mut self, join: RaceJoin) -> bool {
-        if let Ok(token) = Uuid::parse_str(&join.token.as_str()) {
-            if let Some(player) = self.lobby.get_player(token) {
-                if let Some(race) = self.races.get_mut(&join.room) {
+    pub async fn join_race(&self, join: RaceJoin) -> bool {
+        if let Some(player) = self.get_lobby_player(&join.token).await {
+            if let Some(room) = self.get_room(&join.room).await {
+                return room.call(move |race| {
                     if race.is_joinable(&join) {
                         info!(" Player {} join into race {}", player.profile_name, join.room);
-                        race.join(player);
+                        race.join(&player);
                         return true;
                     }
-                }
+                    false
+                }).await.unwrap_or_default();
             }
         }
         return false;
     }
 
-    pub fn leave_race(&mut self, roomname: String, tokenstr: String) -> bool {
-        if let Some(race) = self.races.get_mut(&roomname) {
-            race.leave(&tokenstr);
-            return true;
+    pub async fn spectate_race(&self, join: RaceJoin) -> bool {
+        if let Ok(token) = Uuid::parse_str(join.token.as_str()) {
+            self.force_leave_race(&token).await;
+            if let Some(player) = self.get_lobby_player(&join.token).await {
+                if let Some(room) = self.get_room(&join.room).await {
+                    return room.call(move |race| {
+                        if race.is_spectatable(&join) {
+                            info!(" Player {} spectate race {}", player.profile_name, join.room);
+                            race.spectate(&player);
+                            return true;
+                        }
+                        false
+                    }).await.unwrap_or_default();
+                }
+            }
+        }
+        false
+    }
+
+    pub async fn leave_race(&self, roomname: String, tokenstr: String) -> bool {
+        if let Some(room) = self.get_room(&roomname).await {
+            return room.call(move |race| race.leave(&tokenstr)).await.is_some();
         }
         return false;
     }
 
-    pub fn destroy_race(&mut self, roomname: String, tokenstr: String) -> bool {
-        if let Ok(token) = Uuid::parse_str(&tokenstr) {
-            if self.lobby.is_player_exist(Some(&token), None) {
-                self.races.remove(&roomname);
-                return true;
+    pub async fn destroy_race(&self, roomname: String, tokenstr: String) -> bool {
+        if !self.is_race_manageable(&tokenstr, &roomname).await {
+            return false;
+        }
+
+        if let Some(room) = self.get_room(&roomname).await {
+            if room.call(|race| race.is_protected()).await.unwrap_or(true) { // series created by server are never destroyable.
+                return false;
             }
         }
+        self.races.write().await.remove(&roomname).is_some()
+    }
+
+    pub async fn race_player_access(&self, access: &RaceAccess, writer: MetaWriter) -> bool {
+        if !self.is_authorized(&access.token).await {
+            return false;
+        }
+
+        if let Some(room) = self.get_room(&access.room).await {
+            let token = access.token.clone();
+            return room.call(move |race| race.access(&token, writer)).await.unwrap_or_default();
+        }
         return false;
     }
 
-    pub fn race_player_access(&mut self, access: &RaceAccess, writer: Arc<Mutex<OwnedWriteHalf>>) -> bool {
-        if let Some(race) = self.races.get_mut(&access.room) {
-            return race.access(&access.token, writer);
+    pub async fn race_player_disconnect(&self, access: &RaceAccess, writer: &MetaWriter) -> bool {
+        if let Some(room) = self.get_room(&access.room).await {
+            let (token, writer) = (access.token.clone(), writer.clone());
+            return room.call(move |race| race.disconnect(&token, &writer)).await.unwrap_or_default();
         }
         return false;
     }
 
-    pub fn update_player_state(&mut self, update: &RaceUpdate) -> bool {
-        if let Some(race) = self.races.get_mut(&update.room) {
-            return race.update_player_state(&update.token, update.state.clone());
+    pub async fn update_player_state(&self, update: &RaceUpdate) -> bool {
+        if !self.is_authorized(&update.token).await {
+            return false;
+        }
+
+        if let Some(room) = self.get_room(&update.room).await {
+            let update = update.clone();
+            return room.call(move |race| race.update_player_state(&update.token, update.state)).await.unwrap_or_default();
         }
         return false;
     }
 
-    pub fn update_player_race_data(&mut self, data: MetaRaceData) -> bool {
-        if let Some(race) = self.races.get_mut(&data.room) {
-            return race.update_player_data(&data.token, data.clone());
+    /// Telemetry is never waited for, a lagging room drops it instead of stalling the connection.
+    pub async fn update_player_race_data(&self, data: MetaRaceData) -> bool {
+        if !self.is_authorized(&data.token).await {
+            return false;
+        }
+
+        if let Some(room) = self.get_room(&data.room).await {
+            return room.try_cast(move |race| {
+                race.update_player_data(&data.token.clone(), data);
+            });
         }
         return false;
     }
 
-    pub async fn load_image(&mut self, image: &String) -> Option<Vec<u8>> {
-        let path = std::env::current_exe().unwrap().parent().unwrap().join("templates").join("assets");
+    pub async fn update_player_latency(&self, access: &RaceAccess, latency: u32) -> bool {
+        if !self.is_authorized(&access.token).await {
+            return false;
+        }
+
+        if let Some(room) = self.get_room(&access.room).await {
+            let token = access.token.clone();
+            return room.try_cast(move |race| {
+                race.update_player_latency(&token, latency);
+            });
+        }
+        return false;
+    }
+
+    pub async fn load_image(&self, image: &String) -> Option<Vec<u8>> {
+        let path = config::get().paths.get_asset_dir();
         let image_file = path.join(image);
         tokio::fs::read(image_file).await.ok()
     }
 
-    pub async fn load_file(&mut self, file: &String) -> Option<Vec<u8>> {
-        let path = std::env::current_exe().unwrap().parent().unwrap().join("downloads");
+    pub async fn load_file(&self, file: &String) -> Option<Vec<u8>> {
+        let path = config::get().paths.get_download_dir();
         let filepath = path.join(file);
         tokio::fs::read(filepath).await.ok()
     }
-}
\ No newline at end of file
+}
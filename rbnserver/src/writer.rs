@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use log::warn;
+use rbnproto::metaapi::{encode_frame, DataFormat, MetaRevision};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::{timeout, Duration};
+use crate::config;
+
+/// Frames a slow client may lag behind before its connection is dropped.
+static META_QUEUE_LEN: usize = 64;
+
+/// An encoded frame shared by every recipient of a broadcast.
+#[derive(Clone)]
+pub struct MetaFrame {
+    format: DataFormat,
+    bytes: Arc<[u8]>,
+}
+
+impl MetaFrame {
+    pub fn encode<T: Serialize>(format: DataFormat, body: &T) -> Option<Self> {
+        match encode_frame(format.clone(), body) {
+            Ok(bytes) => Some(Self { format, bytes: bytes.into() }),
+            Err(e) => {
+                warn!("failed to encode frame {:?}: {}", format, e);
+                None
+            }
+        }
+    }
+
+    /// Race data supersedes the previous one, only the latest is worth sending.
+    fn is_coalescable(&self) -> bool {
+        matches!(self.format, DataFormat::FmtSyncRaceData)
+    }
+}
+
+/// Write side of a meta connection served by its own task, senders never wait
+/// on the socket: race data is coalesced and a client falling behind the
+/// ordered queue gets dropped.
+#[derive(Clone)]
+pub struct MetaWriter {
+    queue: mpsc::Sender<MetaFrame>,
+    latest: Arc<watch::Sender<Option<MetaFrame>>>,
+    closing: Arc<Notify>,
+    /// Protocol revision of the peer, known once the handshake is done.
+    revision: MetaRevision,
+}
+
+impl MetaWriter {
+    pub fn spawn(mut writer: OwnedWriteHalf) -> Self {
+        let (queue, mut queue_rx) = mpsc::channel::<MetaFrame>(META_QUEUE_LEN);
+        let (latest, mut latest_rx) = watch::channel::<Option<MetaFrame>>(None);
+        let closing = Arc::new(Notify::new());
+        let closed = closing.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    biased;
+                    _ = closed.notified() => break,
+                    frame = queue_rx.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    changed = latest_rx.changed() => match changed {
+                        Ok(()) => match latest_rx.borrow_and_update().clone() {
+                            Some(frame) => frame,
+                            None => continue,
+                        },
+                        Err(_) => break,
+                    },
+                };
+
+                // a peer gone without closing stalls the write until the socket gives up.
+                let deadline = Duration::from_secs(config::get().timeout.heartbeat as u64);
+                match timeout(deadline, writer.write_all(&frame.bytes)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => break,
+                    Err(_) => {
+                        warn!("drop meta connection, write stuck for {:?}", deadline);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self { queue, latest: Arc::new(latest), closing, revision: MetaRevision::default() }
+    }
+
+    /// Queue a frame without waiting, false once the connection is gone.
+    pub fn send(&self, frame: &MetaFrame) -> bool {
+        if frame.is_coalescable() {
+            self.latest.send_replace(Some(frame.clone()));
+            return !self.is_closed();
+        }
+
+        match self.queue.try_send(frame.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("drop slow meta connection, {} frames behind", META_QUEUE_LEN);
+                self.close();
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    pub fn send_body<T: Serialize>(&self, format: DataFormat, body: &T) -> bool {
+        match MetaFrame::encode(format, body) {
+            Some(frame) => self.send(&frame),
+            None => false,
+        }
+    }
+
+    pub fn set_revision(&mut self, revision: MetaRevision) {
+        self.revision = revision;
+    }
+
+    pub fn get_revision(&self) -> MetaRevision {
+        self.revision
+    }
+
+    pub fn close(&self) {
+        self.closing.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+
+    /// Resolves once the writer task has stopped, the reader should stop as well.
+    pub async fn closed(&self) {
+        self.queue.closed().await
+    }
+
+    pub fn same_connection(&self, other: &MetaWriter) -> bool {
+        self.queue.same_channel(&other.queue)
+    }
+}